@@ -0,0 +1,143 @@
+use std::fmt;
+
+use tokio_tungstenite::tungstenite::protocol::Message;
+
+use crate::networker::SerializableMessage;
+
+/// How messages look on the wire. Each connection settles on one while it's being opened, using
+/// the `Sec-WebSocket-Protocol` header, and anything that doesn't ask for one gets JSON.
+///
+/// The binary ones are only around if their cargo features (`msgpack` and `cbor`) are turned on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Codec {
+    #[default]
+    Json,
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+impl Codec {
+    /// Everything this build can speak, smallest first, which is also the order clients ask for
+    /// them in.
+    pub const ALL: &[Self] = &[
+        #[cfg(feature = "msgpack")]
+        Self::MessagePack,
+        #[cfg(feature = "cbor")]
+        Self::Cbor,
+        Self::Json,
+    ];
+
+    /// The name it goes by in `Sec-WebSocket-Protocol`.
+    pub fn get_protocol(self) -> &'static str {
+        match self {
+            Self::Json => "sillirc.json",
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => "sillirc.msgpack",
+            #[cfg(feature = "cbor")]
+            Self::Cbor => "sillirc.cbor",
+        }
+    }
+
+    pub fn from_protocol(protocol: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|codec| codec.get_protocol() == protocol.trim())
+    }
+
+    /// What a client asks for: every protocol it knows, comma separated.
+    pub fn offer() -> String {
+        Self::ALL
+            .iter()
+            .map(|codec| codec.get_protocol())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Picks the first protocol in a client's `offer` that we know too, if there is one.
+    pub fn negotiate(offer: &str) -> Option<Self> {
+        offer.split(',').find_map(Self::from_protocol)
+    }
+
+    /// JSON still goes out as a binary frame, since that's what it's always been.
+    ///
+    /// # Errors
+    /// If `message` can't be encoded, which shouldn't really happen.
+    pub fn encode(self, message: &SerializableMessage) -> Result<Message, CodecError> {
+        let bytes = match self {
+            Self::Json => serde_json::to_vec(message).map_err(CodecError::Json)?,
+            #[cfg(feature = "msgpack")]
+            // Field names are kept, so fields left out (and ones added later) don't throw
+            // everything after them off.
+            Self::MessagePack => {
+                rmp_serde::to_vec_named(message).map_err(CodecError::MessagePackEncode)?
+            }
+            #[cfg(feature = "cbor")]
+            Self::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(message, &mut bytes).map_err(CodecError::CborEncode)?;
+                bytes
+            }
+        };
+        Ok(Message::binary(bytes))
+    }
+
+    /// # Errors
+    /// If `bytes` isn't a message in this encoding.
+    pub fn decode(self, bytes: &[u8]) -> Result<SerializableMessage, CodecError> {
+        match self {
+            Self::Json => serde_json::from_slice(bytes).map_err(CodecError::Json),
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => {
+                rmp_serde::from_slice(bytes).map_err(CodecError::MessagePackDecode)
+            }
+            #[cfg(feature = "cbor")]
+            Self::Cbor => ciborium::from_reader(bytes).map_err(CodecError::CborDecode),
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Json => write!(f, "JSON"),
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => write!(f, "MessagePack"),
+            #[cfg(feature = "cbor")]
+            Self::Cbor => write!(f, "CBOR"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CodecError {
+    Json(serde_json::Error),
+    #[cfg(feature = "msgpack")]
+    MessagePackEncode(rmp_serde::encode::Error),
+    #[cfg(feature = "msgpack")]
+    MessagePackDecode(rmp_serde::decode::Error),
+    #[cfg(feature = "cbor")]
+    CborEncode(ciborium::ser::Error<std::io::Error>),
+    #[cfg(feature = "cbor")]
+    CborDecode(ciborium::de::Error<std::io::Error>),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Json(err) => write!(f, "{err}"),
+            #[cfg(feature = "msgpack")]
+            Self::MessagePackEncode(err) => write!(f, "{err}"),
+            #[cfg(feature = "msgpack")]
+            Self::MessagePackDecode(err) => write!(f, "{err}"),
+            #[cfg(feature = "cbor")]
+            Self::CborEncode(err) => write!(f, "{err}"),
+            #[cfg(feature = "cbor")]
+            Self::CborDecode(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
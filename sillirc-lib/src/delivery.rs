@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use futures::FutureExt as _;
+use futures::channel::oneshot;
+
+use crate::networker::{ErrorCode, SerializableMessage, SerializableMessageType, ignore_unheard};
+use crate::protocol::Capability;
+
+#[derive(Debug)]
+pub enum SendError {
+    /// The message couldn't be saved to the outbox.
+    Outbox(io::Error),
+    /// The connection is gone for good, probably because the reconnect policy gave up.
+    Closed,
+    /// The server said it can't do this.
+    Unsupported(Capability),
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Outbox(err) => write!(f, "couldn't save message to the outbox: {err}"),
+            Self::Closed => write!(f, "not connected to a server"),
+            Self::Unsupported(capability) => {
+                write!(f, "the server doesn't do {}", capability.get_name())
+            }
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
+
+impl From<io::Error> for SendError {
+    fn from(err: io::Error) -> Self {
+        Self::Outbox(err)
+    }
+}
+
+/// Why a message never made it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeliveryError {
+    /// The server turned it down.
+    Rejected {
+        code: Option<ErrorCode>,
+        reason: String,
+    },
+    /// The connection went before the server said anything about it, so who knows.
+    Lost,
+}
+
+impl fmt::Display for DeliveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Rejected { reason, .. } => write!(f, "{reason}"),
+            Self::Lost => write!(f, "the connection dropped before it got there"),
+        }
+    }
+}
+
+impl std::error::Error for DeliveryError {}
+
+/// The ID the server stored a message under, if it stored it at all.
+pub type Delivered = Result<Option<u64>, DeliveryError>;
+
+/// Finishes once the server has said what it thinks of a message.
+pub struct Delivery {
+    client_id: u64,
+    rx: oneshot::Receiver<Delivered>,
+}
+
+impl Delivery {
+    pub fn get_client_id(&self) -> u64 {
+        self.client_id
+    }
+}
+
+impl Future for Delivery {
+    type Output = Delivered;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Whoever was going to tell us is gone, which only happens once the message is lost.
+        self.rx
+            .poll_unpin(cx)
+            .map(|delivered| delivered.unwrap_or(Err(DeliveryError::Lost)))
+    }
+}
+
+struct Waiting {
+    tx: oneshot::Sender<Delivered>,
+    /// Whether it's actually gone out, rather than waiting for a connection.
+    sent: bool,
+}
+
+/// Messages the server hasn't got back to us about yet, by client ID.
+#[derive(Clone, Default)]
+pub(crate) struct Pending {
+    waiting: Arc<Mutex<HashMap<u64, Waiting>>>,
+}
+
+impl Pending {
+    pub fn track(&self, client_id: u64) -> Delivery {
+        let (tx, rx) = oneshot::channel();
+        self.waiting
+            .lock()
+            .expect("Pending lock failed")
+            .insert(client_id, Waiting { tx, sent: false });
+        Delivery { client_id, rx }
+    }
+
+    pub fn forget(&self, client_id: u64) {
+        self.waiting
+            .lock()
+            .expect("Pending lock failed")
+            .remove(&client_id);
+    }
+
+    pub fn mark_sent(&self, client_id: u64) {
+        if let Some(waiting) = self
+            .waiting
+            .lock()
+            .expect("Pending lock failed")
+            .get_mut(&client_id)
+        {
+            waiting.sent = true;
+        }
+    }
+
+    /// Settles whatever `message` is an `Ack` or `Error` for, if anything.
+    pub fn resolve(&self, message: &SerializableMessage) {
+        let delivered = match message.get_message_type() {
+            SerializableMessageType::Ack => Ok(message.get_id()),
+            SerializableMessageType::Error => Err(DeliveryError::Rejected {
+                code: message.get_error(),
+                reason: message.get_content(),
+            }),
+            _ => return,
+        };
+
+        let Some(client_id) = message.get_client_id() else {
+            return;
+        };
+        let Some(waiting) = self
+            .waiting
+            .lock()
+            .expect("Pending lock failed")
+            .remove(&client_id)
+        else {
+            return;
+        };
+
+        ignore_unheard(waiting.tx.send(delivered));
+    }
+
+    /// The server won't be answering anything it already got once the connection drops. Ones that
+    /// haven't gone out yet still get their chance on the next connection.
+    pub fn lose_sent(&self) {
+        self.waiting
+            .lock()
+            .expect("Pending lock failed")
+            .retain(|_, waiting| !waiting.sent);
+    }
+
+    pub fn lose_all(&self) {
+        self.waiting.lock().expect("Pending lock failed").clear();
+    }
+}
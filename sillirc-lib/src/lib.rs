@@ -1,2 +1,8 @@
+pub mod codec;
+pub mod delivery;
 pub mod networker;
+pub mod outbox;
+pub mod protocol;
+pub mod reconnect;
+pub mod tls;
 pub mod user;
@@ -1,7 +1,44 @@
+use crate::codec::Codec;
+use crate::delivery::{Delivery, Pending, SendError};
+use crate::outbox::Outbox;
+use crate::protocol::{Capabilities, Capability};
+use crate::reconnect::ReconnectPolicy;
+use crate::tls::TlsOptions;
 use crate::user::User;
-use futures::StreamExt as _;
-use futures::channel::mpsc::UnboundedSender;
-use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
+use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use futures::future::{self, Either};
+use futures::stream::{SplitSink, SplitStream};
+use futures::{SinkExt as _, Stream, StreamExt as _, pin_mut, stream};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, watch};
+use tokio_tungstenite::{
+    Connector, MaybeTlsStream, WebSocketStream, connect_async_tls_with_config,
+    tungstenite::{
+        Error as WsError,
+        client::IntoClientRequest as _,
+        error::{ProtocolError, SubProtocolError},
+        http::{HeaderValue, header::SEC_WEBSOCKET_PROTOCOL},
+        protocol::Message,
+    },
+};
+use uuid::Uuid;
+
+pub const DEFAULT_ROOM: &str = "general";
+
+/// How many connection events can pile up for a listener before it starts missing them.
+const EVENT_BACKLOG: usize = 32;
+
+/// How long to wait for a `Welcome` before assuming the server doesn't know what a `Hello` is.
+const WELCOME_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn default_room() -> String {
+    String::from(DEFAULT_ROOM)
+}
 
 #[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
 pub enum SerializableMessageType {
@@ -9,6 +46,68 @@ pub enum SerializableMessageType {
     Leave = 1,
     Rename = 2,
     Text = 3,
+    Direct = 4,
+    Edit = 5,
+    Delete = 6,
+    Reaction = 7,
+    Typing = 8,
+    Who = 9,
+    Roster = 10,
+    Online = 11,
+    Offline = 12,
+    Error = 13,
+    Register = 14,
+    Login = 15,
+    LoggedIn = 16,
+    Ack = 17,
+    /// The first thing a client says, with what protocol version it speaks and what it can do.
+    Hello = 18,
+    /// The server's answer to `Hello`, saying the same about itself.
+    Welcome = 19,
+}
+
+/// Why the server turned something down, for when the message that says so isn't enough.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The frame wasn't a message at all.
+    Malformed,
+    /// Only `Join`, `Register`, `Login` and `Who` are allowed before joining.
+    NotJoined,
+    NotInRoom,
+    NicknameTaken,
+    NicknameRegistered,
+    AlreadyRegistered,
+    NotRegistered,
+    WrongPassword,
+    /// Editing or deleting someone else's message.
+    NotYours,
+    InvalidReaction,
+    NoSuchUser,
+    /// Message types only the server gets to send.
+    ServerOnly,
+    RateLimited,
+    /// Something went wrong on the server's end.
+    Internal,
+    /// Whoever was meant to get it doesn't have the capability it needs.
+    Unsupported,
+    /// One from a newer version than us. It still counts as the message being turned down.
+    #[serde(other)]
+    Unknown,
+}
+
+impl ErrorCode {
+    /// Whether this is about the nickname that was asked for, and the way to fix it is picking
+    /// another one or logging in.
+    pub fn is_about_nickname(self) -> bool {
+        matches!(
+            self,
+            Self::NicknameTaken
+                | Self::NicknameRegistered
+                | Self::AlreadyRegistered
+                | Self::NotRegistered
+                | Self::WrongPassword
+        )
+    }
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Clone)]
@@ -16,6 +115,41 @@ pub struct SerializableMessage {
     user: User,
     message_type: SerializableMessageType,
     content: String,
+    #[serde(default = "default_room")]
+    room: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    recipient: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    history: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timestamp: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target: Option<u64>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    edited: bool,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    reactions: BTreeMap<String, u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reply_to: Option<u64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    roster: Vec<User>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<ErrorCode>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    client_id: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    protocol_version: Option<u32>,
+    /// Names rather than `Capability`s, so ones from newer versions don't get in the way.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    capabilities: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    session: Option<Uuid>,
+    /// Fields from newer versions that we don't know about, kept so they go out again as they
+    /// came in.
+    #[serde(flatten)]
+    unknown_fields: BTreeMap<String, serde_json::Value>,
 }
 
 impl SerializableMessage {
@@ -24,6 +158,166 @@ impl SerializableMessage {
             user,
             message_type,
             content,
+            room: default_room(),
+            recipient: None,
+            history: false,
+            id: None,
+            timestamp: None,
+            target: None,
+            edited: false,
+            reactions: BTreeMap::new(),
+            reply_to: None,
+            roster: Vec::new(),
+            error: None,
+            client_id: None,
+            protocol_version: None,
+            capabilities: Vec::new(),
+            session: None,
+            unknown_fields: BTreeMap::new(),
+        }
+    }
+
+    /// Something the server is telling a client went wrong.
+    pub fn new_error(code: ErrorCode, content: String) -> Self {
+        Self {
+            error: Some(code),
+            ..Self::new(User::new_static(), SerializableMessageType::Error, content)
+        }
+    }
+
+    pub fn new_direct(user: User, recipient: String, content: String) -> Self {
+        Self {
+            recipient: Some(recipient),
+            ..Self::new(user, SerializableMessageType::Direct, content)
+        }
+    }
+
+    pub fn set_user(self, new_user: User) -> Self {
+        Self {
+            user: new_user,
+            ..self
+        }
+    }
+
+    pub fn set_room(self, new_room: String) -> Self {
+        Self {
+            room: new_room,
+            ..self
+        }
+    }
+
+    pub fn set_content(self, new_content: String) -> Self {
+        Self {
+            content: new_content,
+            ..self
+        }
+    }
+
+    pub fn set_recipient(self, recipient: String) -> Self {
+        Self {
+            recipient: Some(recipient),
+            ..self
+        }
+    }
+
+    /// The ID of the message an edit, delete or reaction is about.
+    pub fn set_target(self, target: u64) -> Self {
+        Self {
+            target: Some(target),
+            ..self
+        }
+    }
+
+    pub fn set_edited(self, edited: bool) -> Self {
+        Self { edited, ..self }
+    }
+
+    /// How many people reacted with each emoji. On a reaction this is the new tally for the
+    /// message it's about.
+    pub fn set_reactions(self, new_reactions: BTreeMap<String, u32>) -> Self {
+        Self {
+            reactions: new_reactions,
+            ..self
+        }
+    }
+
+    /// The ID of the message this one is answering.
+    pub fn set_reply_to(self, reply_to: u64) -> Self {
+        Self {
+            reply_to: Some(reply_to),
+            ..self
+        }
+    }
+
+    /// Everyone who's online, for answering a `Who`.
+    pub fn set_roster(self, roster: Vec<User>) -> Self {
+        Self { roster, ..self }
+    }
+
+    /// Any number the client likes, which the server sends back on the `Ack` or `Error` for this
+    /// message.
+    pub fn set_client_id(self, client_id: u64) -> Self {
+        Self {
+            client_id: Some(client_id),
+            ..self
+        }
+    }
+
+    /// Client IDs only mean anything to whoever sent the message, so they're taken off before it
+    /// goes anywhere else.
+    pub fn clear_client_id(self) -> Self {
+        Self {
+            client_id: None,
+            ..self
+        }
+    }
+
+    pub fn set_protocol_version(self, protocol_version: u32) -> Self {
+        Self {
+            protocol_version: Some(protocol_version),
+            ..self
+        }
+    }
+
+    pub fn set_capabilities(self, capabilities: Vec<String>) -> Self {
+        Self {
+            capabilities,
+            ..self
+        }
+    }
+
+    /// Sent along with `Hello`. It's different every time a networker starts, so together with a
+    /// client ID it picks out one message, even when the same user's client IDs start over.
+    pub fn set_session(self, session: Uuid) -> Self {
+        Self {
+            session: Some(session),
+            ..self
+        }
+    }
+
+    pub fn set_unknown_fields(self, unknown_fields: BTreeMap<String, serde_json::Value>) -> Self {
+        Self {
+            unknown_fields,
+            ..self
+        }
+    }
+
+    pub fn set_history(self, history: bool) -> Self {
+        Self { history, ..self }
+    }
+
+    pub fn set_id(self, id: u64) -> Self {
+        Self {
+            id: Some(id),
+            ..self
+        }
+    }
+
+    /// `timestamp` is in milliseconds since the Unix epoch, UTC.
+    pub fn set_timestamp(self, timestamp: u64) -> Self {
+        Self {
+            timestamp: Some(timestamp),
+            ..self
         }
     }
 
@@ -38,69 +332,910 @@ impl SerializableMessage {
     pub fn get_content(&self) -> String {
         self.content.clone()
     }
+
+    pub fn get_room(&self) -> String {
+        self.room.clone()
+    }
+
+    pub fn get_recipient(&self) -> Option<String> {
+        self.recipient.clone()
+    }
+
+    pub fn is_history(&self) -> bool {
+        self.history
+    }
+
+    pub fn get_target(&self) -> Option<u64> {
+        self.target
+    }
+
+    pub fn is_edited(&self) -> bool {
+        self.edited
+    }
+
+    pub fn get_reactions(&self) -> BTreeMap<String, u32> {
+        self.reactions.clone()
+    }
+
+    pub fn get_reply_to(&self) -> Option<u64> {
+        self.reply_to
+    }
+
+    pub fn get_roster(&self) -> Vec<User> {
+        self.roster.clone()
+    }
+
+    pub fn get_error(&self) -> Option<ErrorCode> {
+        self.error
+    }
+
+    pub fn get_client_id(&self) -> Option<u64> {
+        self.client_id
+    }
+
+    pub fn get_protocol_version(&self) -> Option<u32> {
+        self.protocol_version
+    }
+
+    pub fn get_capabilities(&self) -> Vec<String> {
+        self.capabilities.clone()
+    }
+
+    pub fn get_session(&self) -> Option<Uuid> {
+        self.session
+    }
+
+    pub fn get_unknown_fields(&self) -> BTreeMap<String, serde_json::Value> {
+        self.unknown_fields.clone()
+    }
+
+    /// The ID the server gave this message, which only ever goes up.
+    pub fn get_id(&self) -> Option<u64> {
+        self.id
+    }
+
+    /// When the server got this message, in milliseconds since the Unix epoch, UTC.
+    pub fn get_timestamp(&self) -> Option<u64> {
+        self.timestamp
+    }
+
+    /// The time of day the server got this message, as `HH:MM` in UTC.
+    pub fn get_utc_time(&self) -> Option<String> {
+        let seconds_today = self.timestamp? / 1000 % (24 * 60 * 60);
+        Some(format!(
+            "{:02}:{:02}",
+            seconds_today / (60 * 60),
+            seconds_today / 60 % 60
+        ))
+    }
+}
+
+/// What gets sent again after reconnecting, so the server knows who we are and where we were.
+#[derive(Default)]
+struct Session {
+    user: Option<User>,
+    password: Option<String>,
+    rooms: BTreeSet<String>,
+}
+
+impl Session {
+    fn remember(&mut self, message: &SerializableMessage) {
+        match message.get_message_type() {
+            SerializableMessageType::Join => {
+                self.rooms.insert(message.get_room());
+            }
+            SerializableMessageType::Leave => {
+                self.rooms.remove(&message.get_room());
+            }
+            SerializableMessageType::Register | SerializableMessageType::Login => {
+                self.password = Some(message.get_content());
+            }
+            // Renames go out under the old name, with the new one in the content.
+            SerializableMessageType::Rename => {
+                self.user = Some(message.get_user().set_username(message.get_content()));
+                return;
+            }
+            _ => {}
+        }
+
+        self.user = Some(message.get_user());
+    }
+
+    /// A fresh connection doesn't know anything about us, so log back in, rejoin everything, and
+    /// find out who's around now.
+    fn rejoin(&self) -> Vec<SerializableMessage> {
+        let Some(user) = self.user.clone() else {
+            return Vec::new();
+        };
+
+        let login = self.password.clone().map(|password| {
+            SerializableMessage::new(user.clone(), SerializableMessageType::Login, password)
+        });
+        let joins = self.rooms.iter().map(|room| {
+            SerializableMessage::new(user.clone(), SerializableMessageType::Join, String::new())
+                .set_room(room.clone())
+        });
+
+        let who =
+            SerializableMessage::new(user.clone(), SerializableMessageType::Who, String::new());
+
+        login.into_iter().chain(joins).chain([who]).collect()
+    }
+
+    /// Whether the server would take `message` from us yet, which it won't until we've joined
+    /// wherever it's going.
+    fn can_send(&self, message: &SerializableMessage) -> bool {
+        match message.get_message_type() {
+            SerializableMessageType::Direct => self.user.is_some(),
+            _ => self.rooms.contains(&message.get_room()),
+        }
+    }
+}
+
+/// What the connection to the server is up to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    /// Trying to get through to the server.
+    Connecting,
+    Connected,
+    /// The connection dropped, or an attempt at one didn't work out.
+    Disconnected {
+        reason: String,
+    },
+    /// Waiting `delay` before attempt number `attempt`.
+    Reconnecting {
+        attempt: u32,
+        delay: Duration,
+    },
+    /// The reconnect policy ran out of attempts, so that's it.
+    GaveUp,
+}
+
+impl ConnectionEvent {
+    pub fn is_connected(&self) -> bool {
+        matches!(self, Self::Connected)
+    }
+}
+
+impl fmt::Display for ConnectionEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Connecting => write!(f, "connecting..."),
+            Self::Connected => write!(f, "connected"),
+            Self::Disconnected { reason } => write!(f, "disconnected: {reason}"),
+            Self::Reconnecting { attempt, delay } => write!(
+                f,
+                "reconnecting in {:.1}s (attempt {attempt})",
+                delay.as_secs_f32()
+            ),
+            Self::GaveUp => write!(f, "couldn't reach the server, giving up"),
+        }
+    }
+}
+
+/// Everything a networker hears about, in the order it happened.
+#[derive(Clone)]
+pub enum Event {
+    Message(Box<SerializableMessage>),
+    Connection(ConnectionEvent),
+    /// Something from the server that isn't a message we know, most likely a kind that's newer
+    /// than we are. `raw` is exactly what came in, in `codec`.
+    Unknown {
+        codec: Codec,
+        raw: Vec<u8>,
+    },
+}
+
+/// For failures there's nobody to tell about: whoever was listening is gone, or the outbox couldn't
+/// forget a message, which only means it gets sent once more than it needed to.
+pub(crate) fn ignore_unheard<T, E>(_result: Result<T, E>) {}
+
+/// Where events go: connection ones are also kept around (the latest one, anyway) for anyone who
+/// wants to check, and sent out to anyone listening for them on their own.
+struct Events {
+    stream: UnboundedSender<Event>,
+    state: watch::Sender<ConnectionEvent>,
+    connection: broadcast::Sender<ConnectionEvent>,
+    /// What the server we're connected to can do, or `None` while we're still finding out.
+    server: watch::Sender<Option<Capabilities>>,
+    pending: Pending,
+    outbox: Outbox,
+}
+
+impl Events {
+    fn emit(&self, event: ConnectionEvent) {
+        self.state.send_replace(event.clone());
+        ignore_unheard(self.connection.send(event.clone()));
+        ignore_unheard(self.stream.unbounded_send(Event::Connection(event)));
+    }
+
+    fn handle(&self, event: Event) {
+        match event {
+            Event::Message(message) => self.receive(*message),
+            event => ignore_unheard(self.stream.unbounded_send(event)),
+        }
+    }
+
+    fn receive(&self, message: SerializableMessage) {
+        // That's between us and the server, nobody else needs to know.
+        if matches!(message.get_message_type(), SerializableMessageType::Welcome) {
+            self.server
+                .send_replace(Some(Capabilities::from_message(&message)));
+            return;
+        }
+
+        self.pending.resolve(&message);
+        if matches!(
+            message.get_message_type(),
+            SerializableMessageType::Ack | SerializableMessageType::Error
+        ) && let Some(client_id) = message.get_client_id()
+        {
+            ignore_unheard(self.outbox.remove(client_id));
+        }
+
+        ignore_unheard(
+            self.stream
+                .unbounded_send(Event::Message(Box::new(message))),
+        );
+    }
+
+    /// Settles a message the server won't ever acknowledge, as well as it's going to be.
+    fn settle(&self, client_id: u64) {
+        self.receive(
+            SerializableMessage::new(
+                User::new_static(),
+                SerializableMessageType::Ack,
+                String::new(),
+            )
+            .set_client_id(client_id),
+        );
+    }
+
+    /// Turns down a message that can't go out, just like the server would.
+    fn refuse(&self, message: &SerializableMessage, code: ErrorCode, reason: String) {
+        self.receive(
+            SerializableMessage::new_error(code, reason)
+                .set_client_id(message.get_client_id().unwrap_or_default()),
+        );
+    }
+}
+
+/// Connects to `url`, and works out which codec the server picked out of the ones we offered.
+/// That's `None` for servers from before codecs (and hellos), which only speak JSON.
+async fn open(url: &str, connector: &Connector) -> Result<(WebSocket, Option<Codec>), WsError> {
+    let mut request = url.into_client_request()?;
+    let offer = HeaderValue::from_str(&Codec::offer()).expect("Protocol names are valid headers");
+    request.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, offer);
+
+    match connect_async_tls_with_config(request, None, false, Some(connector.clone())).await {
+        Ok((ws_stream, response)) => {
+            let codec = response
+                .headers()
+                .get(SEC_WEBSOCKET_PROTOCOL)
+                .and_then(|protocol| protocol.to_str().ok())
+                .and_then(Codec::from_protocol)
+                .unwrap_or_default();
+            Ok((ws_stream, Some(codec)))
+        }
+        // Old servers don't answer the offer at all, so try again without one.
+        Err(WsError::Protocol(ProtocolError::SecWebSocketSubProtocolError(
+            SubProtocolError::NoSubProtocol,
+        ))) => {
+            let connecting =
+                connect_async_tls_with_config(url, None, false, Some(connector.clone()));
+            Ok((connecting.await?.0, None))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Says hello, and passes on whatever turns up until the server says hello back.
+async fn greet(
+    ws_stream: &mut WebSocket,
+    codec: Codec,
+    session: Uuid,
+    events: &Events,
+) -> Result<(), WsError> {
+    let hello = Capabilities::ours()
+        .to_message(SerializableMessageType::Hello)
+        .set_session(session);
+    if let Ok(frame) = codec.encode(&hello) {
+        ws_stream.send(frame).await?;
+    }
+
+    let welcome = async {
+        while let Some(frame) = ws_stream.next().await {
+            let Some(event) = decode(codec, frame?) else {
+                continue;
+            };
+            let welcome = matches!(
+                &event,
+                Event::Message(message)
+                    if matches!(message.get_message_type(), SerializableMessageType::Welcome)
+            );
+            events.handle(event);
+            if welcome {
+                return Ok(());
+            }
+        }
+        Err(WsError::ConnectionClosed)
+    };
+
+    // A server that never answers gets treated like one from before hellos.
+    tokio::time::timeout(WELCOME_TIMEOUT, welcome)
+        .await
+        .unwrap_or(Ok(()))
+}
+
+/// Keeps trying `url` until it works, or `policy` says to stop. `failures` is how many attempts in
+/// a row have failed so far, which picks up where the last connection left off.
+async fn connect_with_backoff(
+    url: &str,
+    policy: &ReconnectPolicy,
+    connector: &Connector,
+    events: &Events,
+    failures: &mut u32,
+) -> Option<(WebSocket, Option<Codec>)> {
+    loop {
+        if *failures > 0 {
+            if policy.gives_up_after(*failures) {
+                events.emit(ConnectionEvent::GaveUp);
+                return None;
+            }
+
+            let delay = policy.delay(*failures);
+            events.emit(ConnectionEvent::Reconnecting {
+                attempt: *failures + 1,
+                delay,
+            });
+            tokio::time::sleep(delay).await;
+        }
+
+        events.emit(ConnectionEvent::Connecting);
+        let reason = match open(url, connector).await {
+            Ok(connection) => return Some(connection),
+            Err(err) => err.to_string(),
+        };
+        events.emit(ConnectionEvent::Disconnected { reason });
+        *failures += 1;
+    }
+}
+
+/// What `frame` was, if it was anything. Text is always JSON, whatever was agreed on.
+fn decode(codec: Codec, frame: Message) -> Option<Event> {
+    let (codec, raw) = match frame {
+        Message::Text(text) => (Codec::Json, Vec::from(text.as_bytes())),
+        Message::Binary(bytes) => (codec, bytes.to_vec()),
+        _ => return None,
+    };
+
+    Some(match codec.decode(&raw) {
+        Ok(message) => Event::Message(Box::new(message)),
+        // Whoever's listening might know what to do with it, even if we don't.
+        Err(_) => Event::Unknown { codec, raw },
+    })
+}
+
+/// Passes on everything the server sends until the connection goes, then says why it went.
+async fn receive_all(mut read: SplitStream<WebSocket>, codec: Codec, events: &Events) -> String {
+    let mut reason = String::from("the server hung up");
+    while let Some(message) = read.next().await {
+        let data = match message {
+            Ok(Message::Close(Some(close))) => {
+                reason = format!("the server hung up ({close})");
+                continue;
+            }
+            Ok(data) => data,
+            Err(err) => return err.to_string(),
+        };
+
+        if let Some(event) = decode(codec, data) {
+            events.handle(event);
+        }
+    }
+    reason
+}
+
+/// Sends everything the networker's given, along with anything in the outbox that can go now.
+/// Only finishes once every `Networker` is gone, or the connection is.
+async fn send_all(
+    rx: &mut UnboundedReceiver<Outgoing>,
+    write: &mut SplitSink<WebSocket, Message>,
+    codec: Codec,
+    session: &mut Session,
+    events: &Events,
+) -> Result<(), WsError> {
+    let server = events
+        .server
+        .borrow()
+        .clone()
+        .unwrap_or_else(Capabilities::legacy);
+    // Without acks there's nothing to wait for, so a message is as done as it'll get once it's out.
+    let settle = |message: &SerializableMessage| {
+        if let Some(client_id) = message.get_client_id()
+            && !server.supports(Capability::Acks)
+        {
+            events.settle(client_id);
+        }
+    };
+
+    loop {
+        let ready = events
+            .outbox
+            .take_ready(|message| session.can_send(message));
+        for message in ready {
+            if let Ok(frame) = codec.encode(&message) {
+                write.send(frame).await?;
+                settle(&message);
+            }
+        }
+
+        let Some(message) = rx.next().await else {
+            return Ok(());
+        };
+        // It might have been fine for the last server, but this one can't do anything with it.
+        if let Some(missing) = server.missing_for(&message) {
+            events.refuse(
+                &message,
+                ErrorCode::Unsupported,
+                format!("The server doesn't do {}", missing.get_name()),
+            );
+            continue;
+        }
+        let frame = match codec.encode(&message) {
+            Ok(frame) => frame,
+            Err(err) => {
+                // Treated just like the server turning it down, since it'd have to.
+                events.refuse(
+                    &message,
+                    ErrorCode::Malformed,
+                    format!("Couldn't encode that as {codec}: {err}"),
+                );
+                continue;
+            }
+        };
+        session.remember(&message);
+        if let Some(client_id) = message.get_client_id() {
+            // Anything in the outbox gets another go if the connection drops, so it's only the
+            // rest that'll never hear back.
+            if events.outbox.contains(client_id) {
+                events.outbox.mark_sent(client_id);
+            } else {
+                events.pending.mark_sent(client_id);
+            }
+        }
+        write.send(frame).await?;
+        settle(&message);
+    }
+}
+
+/// Looks after the connection for as long as there's a `Networker` around to use it, starting a
+/// new one whenever the old one drops.
+async fn run_connection(
+    url: String,
+    policy: ReconnectPolicy,
+    tls: TlsOptions,
+    mut rx: UnboundedReceiver<Outgoing>,
+    events: Events,
+) {
+    let mut session = Session::default();
+    // The same for every connection, so the server can tell what's being sent again after one drops.
+    let session_id = Uuid::new_v4();
+    let connector = Connector::Rustls(tls.client_config());
+    let mut failures = 0;
+    let mut connected_at: Option<Instant> = None;
+
+    loop {
+        // Otherwise a server that hangs up as soon as we're in gets hammered with reconnects.
+        if let Some(connected_at) = connected_at.take() {
+            failures = if policy.dropped_too_soon(connected_at.elapsed()) {
+                failures + 1
+            } else {
+                0
+            };
+        }
+        let Some((mut ws_stream, negotiated)) =
+            connect_with_backoff(&url, &policy, &connector, &events, &mut failures).await
+        else {
+            break;
+        };
+        connected_at = Some(Instant::now());
+
+        let codec = negotiated.unwrap_or_default();
+
+        // This might not be the server we had last time, so there's no telling what it can do
+        // until it says hello back.
+        events.server.send_replace(None);
+        if negotiated.is_some()
+            && let Err(err) = greet(&mut ws_stream, codec, session_id, &events).await
+        {
+            events.emit(ConnectionEvent::Disconnected {
+                reason: err.to_string(),
+            });
+            continue;
+        }
+        // If it didn't, it's from before anyone said hello.
+        let server = events
+            .server
+            .borrow()
+            .clone()
+            .unwrap_or_else(Capabilities::legacy);
+        events.server.send_replace(Some(server.clone()));
+
+        let (mut write, read) = ws_stream.split();
+
+        let rejoin = session
+            .rejoin()
+            .iter()
+            .filter(|message| server.understands(message))
+            .filter_map(|message| codec.encode(message).ok())
+            .map(Ok)
+            .collect::<Vec<_>>();
+        if let Err(err) = write.send_all(&mut stream::iter(rejoin)).await {
+            events.emit(ConnectionEvent::Disconnected {
+                reason: err.to_string(),
+            });
+            continue;
+        }
+        events.emit(ConnectionEvent::Connected);
+
+        let outgoing = send_all(&mut rx, &mut write, codec, &mut session, &events);
+        let incoming = receive_all(read, codec, &events);
+
+        pin_mut!(outgoing, incoming);
+        let reason = match future::select(outgoing, incoming).await {
+            // Running out of things to send means every `Networker` is gone, so we're done here.
+            Either::Left((Ok(()), _)) => return,
+            Either::Left((Err(err), _)) => err.to_string(),
+            Either::Right((reason, _)) => reason,
+        };
+        events.pending.lose_sent();
+        events.outbox.mark_unsent();
+        events.emit(ConnectionEvent::Disconnected { reason });
+    }
+
+    // Nothing's getting sent from here on, so there's no point waiting to hear back about it.
+    rx.close();
+    events.pending.lose_all();
+}
+
+/// A message on its way out. It only gets encoded once we know what the connection speaks.
+type Outgoing = SerializableMessage;
+
+type WebSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Anything about how a networker connects that isn't always the same. The defaults reconnect
+/// forever, keep the outbox in memory, and trust the usual certificate authorities.
+#[derive(Clone, Default)]
+pub struct ConnectOptions {
+    policy: ReconnectPolicy,
+    outbox: Outbox,
+    /// How a `wss://` server's certificate gets checked.
+    tls: TlsOptions,
+}
+
+impl ConnectOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_policy(self, policy: ReconnectPolicy) -> Self {
+        Self { policy, ..self }
+    }
+
+    pub fn set_outbox(self, outbox: Outbox) -> Self {
+        Self { outbox, ..self }
+    }
+
+    pub fn set_tls(self, tls: TlsOptions) -> Self {
+        Self { tls, ..self }
+    }
 }
 
 #[derive(Clone)]
 pub struct Networker {
-    tx: UnboundedSender<Message>,
-    // rx: UnboundedReceiver<Message>,
-    // ws_stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
-    // write: SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>,
-    // read: SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>,
+    tx: UnboundedSender<Outgoing>,
+    next_client_id: Arc<AtomicU64>,
+    pending: Pending,
+    outbox: Outbox,
+    state: watch::Receiver<ConnectionEvent>,
+    connection: broadcast::Sender<ConnectionEvent>,
+    server: watch::Receiver<Option<Capabilities>>,
 }
 
 impl Networker {
-    pub async fn new<F, Fut>(url: &str, rx_callback: F) -> Self
+    /// Like `connect`, but with every message handed to `rx_callback` instead.
+    pub async fn new<F, Fut>(url: &str, options: ConnectOptions, rx_callback: F) -> Self
     where
         F: Fn(SerializableMessage) -> Fut + Send + Sync + 'static,
         Fut: Future<Output = ()> + Send + 'static,
     {
-        let (tx, rx) = futures::channel::mpsc::unbounded();
-        let (ws_stream, _) = loop {
-            if let Ok(content) = connect_async(url).await {
-                break content;
+        let (networker, events) = Self::connect(url, options);
+
+        tokio::spawn(events.for_each(move |event| {
+            let message = match event {
+                Event::Message(message) => Some(*message),
+                Event::Connection(_) | Event::Unknown { .. } => None,
+            };
+            let received = message.map(&rx_callback);
+            async move {
+                if let Some(received) = received {
+                    received.await;
+                }
             }
+        }));
+
+        networker
+    }
+
+    /// Connects to `url`, and reconnects whenever the connection drops, as often as the reconnect
+    /// policy allows. Everything joined (and logged in to) gets rejoined each time. Returns
+    /// straight away, and anything sent before the connection is up waits for it, unless the
+    /// policy gives up.
+    ///
+    /// Messages worth keeping stay in the outbox until the server acknowledges them, and get sent
+    /// again (in order) after reconnecting, once we're back in their room. That includes any left
+    /// over in it from last time.
+    ///
+    /// Everything that comes in, and everything that happens to the connection, comes out of the
+    /// stream, which ends once the networker is gone or the policy gave up.
+    pub fn connect(
+        url: &str,
+        options: ConnectOptions,
+    ) -> (Self, impl Stream<Item = Event> + Unpin + Send + 'static) {
+        let ConnectOptions {
+            policy,
+            outbox,
+            tls,
+        } = options;
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        let (stream_tx, stream) = futures::channel::mpsc::unbounded();
+        let (state_tx, state) = watch::channel(ConnectionEvent::Connecting);
+        let (connection, _) = broadcast::channel(EVENT_BACKLOG);
+        let (server_tx, server) = watch::channel(None);
+        let pending = Pending::default();
+
+        tokio::spawn(run_connection(
+            url.to_owned(),
+            policy,
+            tls,
+            rx,
+            Events {
+                stream: stream_tx,
+                state: state_tx,
+                connection: connection.clone(),
+                server: server_tx,
+                pending: pending.clone(),
+                outbox: outbox.clone(),
+            },
+        ));
+
+        let networker = Self {
+            tx,
+            // Leftovers from last time already have client IDs, so carry on after them.
+            next_client_id: Arc::new(AtomicU64::new(
+                outbox.get_last_client_id().unwrap_or_default() + 1,
+            )),
+            pending,
+            outbox,
+            state,
+            connection,
+            server,
         };
-        let (write, read) = ws_stream.split();
+        (networker, stream)
+    }
+
+    /// Our own messages the server hasn't acknowledged yet, oldest first.
+    pub fn get_outbox(&self) -> Vec<SerializableMessage> {
+        self.outbox.get_messages()
+    }
+
+    /// What the connection is up to right now.
+    pub fn get_connection_state(&self) -> ConnectionEvent {
+        self.state.borrow().clone()
+    }
 
-        tokio::spawn(rx.map(Ok).forward(write));
+    /// What the server can do, as far as we know. That's `None` until it's said (or been around
+    /// long enough without saying that it's clearly from before it could).
+    pub fn get_server_capabilities(&self) -> Option<Capabilities> {
+        self.server.borrow().clone()
+    }
 
-        let rx_callback = std::sync::Arc::new(rx_callback);
-        tokio::spawn({
-            let rx_callback = rx_callback.clone();
-            read.for_each(move |message| {
-                let rx_callback = rx_callback.clone();
-                async move {
-                    let Ok(data) = message else { return };
+    /// Hears about the connection coming and going from now on.
+    pub fn connection_events(&self) -> broadcast::Receiver<ConnectionEvent> {
+        self.connection.subscribe()
+    }
 
-                    let Ok(text) = data.into_text() else { return };
+    /// Logs in to `user`'s registered nickname, which has to happen before it can be used. The
+    /// server answers with `LoggedIn`, or an `Error` if the password is wrong.
+    ///
+    /// # Errors
+    /// The same as `send`.
+    pub async fn login(&mut self, user: User, password: String) -> Result<Delivery, SendError> {
+        self.send(SerializableMessage::new(
+            user,
+            SerializableMessageType::Login,
+            password,
+        ))
+        .await
+    }
 
-                    let Ok(msg) = serde_json::from_str(&text) else {
-                        return;
-                    };
+    /// Registers `user`'s nickname so nobody else can use it without `password`, and logs in.
+    ///
+    /// # Errors
+    /// The same as `send`.
+    pub async fn register(&mut self, user: User, password: String) -> Result<Delivery, SendError> {
+        self.send(SerializableMessage::new(
+            user,
+            SerializableMessageType::Register,
+            password,
+        ))
+        .await
+    }
 
-                    rx_callback(msg).await;
-                }
-            })
-        });
+    /// Sends `message`, tagged with a client ID (unless it already has one) that the server's
+    /// `Ack` or `Error` for it will carry. The `Delivery` finishes once one of those turns up.
+    ///
+    /// # Errors
+    /// If the server can't do anything with `message`, it can't be saved to the outbox, or the
+    /// connection is gone for good.
+    pub async fn send(&mut self, message: SerializableMessage) -> Result<Delivery, SendError> {
+        let client_id = message
+            .get_client_id()
+            .unwrap_or_else(|| self.next_client_id.fetch_add(1, Ordering::Relaxed));
+        let message = message.set_client_id(client_id);
+        // If we don't know yet, it gets checked again once we do, just before it goes out.
+        if let Some(missing) = self
+            .server
+            .borrow()
+            .as_ref()
+            .and_then(|server| server.missing_for(&message))
+        {
+            return Err(SendError::Unsupported(missing));
+        }
 
-        Self {
-            tx,
-            // rx,
-            // ws_stream,
-            // write,
-            // read
+        if Outbox::keeps(&message) {
+            self.outbox.push(message.clone())?;
         }
+
+        let delivery = self.pending.track(client_id);
+        self.tx.unbounded_send(message).map_err(|_closed| {
+            self.pending.forget(client_id);
+            ignore_unheard(self.outbox.remove(client_id));
+            SendError::Closed
+        })?;
+        Ok(delivery)
     }
+}
 
-    pub async fn send(&mut self, message: SerializableMessage) {
-        let Ok(message) = serde_json::to_string(&message) else {
-            return;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `Text` from some later version, with a field nobody's thought of yet.
+    const FUTURE_TEXT: &str = r#"{"user":{"username":"alice","color":[1,2,3]},"message_type":"Text","content":"hi","thread":{"id":7,"title":"later"}}"#;
+    /// A message type from some later version.
+    const FUTURE_TYPE: &str =
+        r#"{"user":{"username":"alice","color":[1,2,3]},"message_type":"Poll","content":"?"}"#;
+
+    /// `json` as it'd come in from a server speaking `codec`.
+    fn frame(codec: Codec, json: &str) -> Message {
+        let value: serde_json::Value = serde_json::from_str(json).expect("Invalid test JSON");
+        let bytes = match codec {
+            Codec::Json => serde_json::to_vec(&value).expect("Failed to encode JSON"),
+            #[cfg(feature = "msgpack")]
+            Codec::MessagePack => rmp_serde::to_vec_named(&value).expect("Failed to encode"),
+            #[cfg(feature = "cbor")]
+            Codec::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(&value, &mut bytes).expect("Failed to encode");
+                bytes
+            }
         };
+        Message::binary(bytes)
+    }
 
-        #[expect(clippy::match_single_binding)]
-        match self.tx.clone().unbounded_send(Message::binary(message)) {
-            _ => {}
+    #[test]
+    fn unknown_fields_are_kept() {
+        for &codec in Codec::ALL {
+            let Some(Event::Message(message)) = decode(codec, frame(codec, FUTURE_TEXT)) else {
+                panic!("{codec} should have decoded a message");
+            };
+            assert_eq!(message.get_content(), "hi", "{codec}");
+            assert_eq!(
+                message.get_unknown_fields().get("thread"),
+                Some(&serde_json::json!({"id": 7, "title": "later"})),
+                "{codec}"
+            );
+
+            // They go back out the way they came in, too.
+            let Some(Event::Message(again)) =
+                decode(codec, codec.encode(&message).expect("Failed to re-encode"))
+            else {
+                panic!("{codec} should have decoded its own message");
+            };
+            assert_eq!(
+                again.get_unknown_fields(),
+                message.get_unknown_fields(),
+                "{codec}"
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_message_types_are_passed_on() {
+        for &codec in Codec::ALL {
+            let sent = frame(codec, FUTURE_TYPE);
+            let Some(Event::Unknown {
+                codec: raw_codec,
+                raw,
+            }) = decode(codec, sent.clone())
+            else {
+                panic!("{codec} should have passed it on as unknown");
+            };
+            assert_eq!(raw_codec, codec);
+            assert_eq!(raw, sent.into_data().to_vec(), "{codec}");
+        }
+    }
+
+    #[test]
+    fn unknown_error_codes_still_turn_messages_down() {
+        let future_error =
+            r#"{"user":{},"message_type":"Error","content":"no","error":"TooSilly","client_id":4}"#;
+        for &codec in Codec::ALL {
+            let Some(Event::Message(error)) = decode(codec, frame(codec, future_error)) else {
+                panic!("{codec} should have decoded the error");
+            };
+            assert_eq!(error.get_error(), Some(ErrorCode::Unknown), "{codec}");
+            assert_eq!(error.get_client_id(), Some(4), "{codec}");
+        }
+    }
+
+    #[test]
+    fn text_frames_are_json() {
+        for &codec in Codec::ALL {
+            assert!(
+                matches!(
+                    decode(codec, Message::text(FUTURE_TEXT)),
+                    Some(Event::Message(_))
+                ),
+                "{codec}"
+            );
+            assert!(
+                matches!(
+                    decode(codec, Message::text(FUTURE_TYPE)),
+                    Some(Event::Unknown {
+                        codec: Codec::Json,
+                        ..
+                    })
+                ),
+                "{codec}"
+            );
         }
     }
+
+    #[test]
+    fn rejoining_after_a_rename_keeps_the_new_name() {
+        let user = User::new(String::from("alice"));
+        let mut session = Session::default();
+        session.remember(&SerializableMessage::new(
+            user.clone(),
+            SerializableMessageType::Join,
+            String::new(),
+        ));
+        session.remember(&SerializableMessage::new(
+            user,
+            SerializableMessageType::Rename,
+            String::from("bob"),
+        ));
+
+        for message in session.rejoin() {
+            assert_eq!(message.get_user().get_username(), "bob");
+        }
+    }
+
+    #[test]
+    fn control_frames_are_nothing() {
+        assert!(decode(Codec::Json, Message::Ping(Vec::new().into())).is_none());
+    }
 }
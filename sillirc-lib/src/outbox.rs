@@ -0,0 +1,184 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::networker::{SerializableMessage, SerializableMessageType};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Handed to the networker, but not written to the connection yet.
+    Queued,
+    /// Written to the current connection, and waiting to hear back.
+    Sent,
+    /// Needs sending again, because the connection it went out on dropped, or it was left over
+    /// from last time.
+    Waiting,
+}
+
+struct Inner {
+    /// By client ID, which goes up, so this is also the order they were sent in.
+    messages: BTreeMap<u64, (SerializableMessage, State)>,
+    path: Option<PathBuf>,
+}
+
+impl Inner {
+    /// Writes everything out again, one JSON message per line. It's only ever a handful of
+    /// messages, so keeping it simple beats keeping it clever.
+    fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let mut lines = String::new();
+        for (message, _) in self.messages.values() {
+            lines.push_str(&serde_json::to_string(message)?);
+            lines.push('\n');
+        }
+        fs::write(path, lines)
+    }
+}
+
+/// Messages the server hasn't acknowledged yet, kept until it does so they can be sent again after
+/// reconnecting (or restarting, if it's kept in a file).
+#[derive(Clone)]
+pub struct Outbox {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Outbox {
+    /// An outbox that's forgotten once the program stops.
+    pub fn memory() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                messages: BTreeMap::new(),
+                path: None,
+            })),
+        }
+    }
+
+    /// An outbox kept in the file at `path`, picking up whatever was left in it last time.
+    ///
+    /// # Errors
+    /// If the file exists but can't be read.
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err),
+        };
+
+        // A line that doesn't make sense anymore isn't worth refusing to start over.
+        let messages = contents
+            .lines()
+            .filter_map(|line| serde_json::from_str::<SerializableMessage>(line).ok())
+            .filter_map(|message| Some((message.get_client_id()?, (message, State::Waiting))))
+            .collect();
+
+        Ok(Self {
+            inner: Arc::new(Mutex::new(Inner {
+                messages,
+                path: Some(path),
+            })),
+        })
+    }
+
+    /// Whether `message` is worth holding on to. Typing and the like mean nothing by the time
+    /// they'd be sent again, and logins aren't going anywhere near a file. Reactions toggle, so
+    /// one that did make it before the connection dropped would be taken back by sending it again.
+    pub fn keeps(message: &SerializableMessage) -> bool {
+        matches!(
+            message.get_message_type(),
+            SerializableMessageType::Text
+                | SerializableMessageType::Direct
+                | SerializableMessageType::Edit
+                | SerializableMessageType::Delete
+        )
+    }
+
+    /// Everything still waiting to be acknowledged, oldest first.
+    pub fn get_messages(&self) -> Vec<SerializableMessage> {
+        self.lock()
+            .messages
+            .values()
+            .map(|(message, _)| message.clone())
+            .collect()
+    }
+
+    pub fn get_last_client_id(&self) -> Option<u64> {
+        self.lock().messages.keys().next_back().copied()
+    }
+
+    pub fn contains(&self, client_id: u64) -> bool {
+        self.lock().messages.contains_key(&client_id)
+    }
+
+    /// Holds on to `message`, which needs a client ID to be found again.
+    ///
+    /// # Errors
+    /// If the outbox's file can't be written.
+    pub fn push(&self, message: SerializableMessage) -> io::Result<()> {
+        let Some(client_id) = message.get_client_id() else {
+            return Ok(());
+        };
+
+        let mut inner = self.lock();
+        inner.messages.insert(client_id, (message, State::Queued));
+        inner.save()
+    }
+
+    /// Lets go of a message once the server's acknowledged it, or turned it down.
+    ///
+    /// # Errors
+    /// If the outbox's file can't be written.
+    pub fn remove(&self, client_id: u64) -> io::Result<()> {
+        let mut inner = self.lock();
+        if inner.messages.remove(&client_id).is_none() {
+            return Ok(());
+        }
+        inner.save()
+    }
+
+    pub fn mark_sent(&self, client_id: u64) {
+        if let Some((_, state)) = self.lock().messages.get_mut(&client_id) {
+            *state = State::Sent;
+        }
+    }
+
+    /// The connection dropped, so nothing sent on it is going to be acknowledged.
+    pub fn mark_unsent(&self) {
+        for (_, state) in self.lock().messages.values_mut() {
+            if *state == State::Sent {
+                *state = State::Waiting;
+            }
+        }
+    }
+
+    /// Takes out everything waiting to be sent again that `ready` says can go now, in order.
+    pub fn take_ready(
+        &self,
+        ready: impl Fn(&SerializableMessage) -> bool,
+    ) -> Vec<SerializableMessage> {
+        self.lock()
+            .messages
+            .values_mut()
+            .filter(|(message, state)| *state == State::Waiting && ready(message))
+            .map(|(message, state)| {
+                *state = State::Sent;
+                message.clone()
+            })
+            .collect()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().expect("Outbox lock failed")
+    }
+}
+
+impl Default for Outbox {
+    fn default() -> Self {
+        Self::memory()
+    }
+}
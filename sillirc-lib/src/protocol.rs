@@ -0,0 +1,206 @@
+use std::collections::BTreeSet;
+
+use crate::networker::{SerializableMessage, SerializableMessageType};
+use crate::user::User;
+
+/// Goes up whenever `SerializableMessage` changes in a way the other end needs to know about.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Things either end might not be able to do, named in `Hello` and `Welcome`. Anything not in here
+/// (joining, talking, renaming, errors and the handshake itself) everyone can do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Capability {
+    /// `Ack`s for messages that went through.
+    Acks,
+    Direct,
+    /// `Edit` and `Delete`.
+    Edits,
+    Reactions,
+    Typing,
+    /// `Who`, `Roster`, `Online` and `Offline`.
+    Presence,
+    /// `Register`, `Login` and `LoggedIn`.
+    Accounts,
+}
+
+impl Capability {
+    pub const ALL: &[Self] = &[
+        Self::Acks,
+        Self::Direct,
+        Self::Edits,
+        Self::Reactions,
+        Self::Typing,
+        Self::Presence,
+        Self::Accounts,
+    ];
+
+    pub fn get_name(self) -> &'static str {
+        match self {
+            Self::Acks => "acks",
+            Self::Direct => "direct",
+            Self::Edits => "edits",
+            Self::Reactions => "reactions",
+            Self::Typing => "typing",
+            Self::Presence => "presence",
+            Self::Accounts => "accounts",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|capability| capability.get_name() == name)
+    }
+
+    /// What it takes to understand a message of `message_type`, if anything.
+    pub fn needed_for(message_type: &SerializableMessageType) -> Option<Self> {
+        match message_type {
+            SerializableMessageType::Ack => Some(Self::Acks),
+            SerializableMessageType::Direct => Some(Self::Direct),
+            SerializableMessageType::Edit | SerializableMessageType::Delete => Some(Self::Edits),
+            SerializableMessageType::Reaction => Some(Self::Reactions),
+            SerializableMessageType::Typing => Some(Self::Typing),
+            SerializableMessageType::Who
+            | SerializableMessageType::Roster
+            | SerializableMessageType::Online
+            | SerializableMessageType::Offline => Some(Self::Presence),
+            SerializableMessageType::Register
+            | SerializableMessageType::Login
+            | SerializableMessageType::LoggedIn => Some(Self::Accounts),
+            SerializableMessageType::Join
+            | SerializableMessageType::Leave
+            | SerializableMessageType::Rename
+            | SerializableMessageType::Text
+            | SerializableMessageType::Error
+            | SerializableMessageType::Hello
+            | SerializableMessageType::Welcome => None,
+        }
+    }
+}
+
+/// What the other end said it can do.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Capabilities {
+    version: u32,
+    supported: BTreeSet<Capability>,
+}
+
+impl Capabilities {
+    /// Everything this build can do.
+    pub fn ours() -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            supported: Capability::ALL.iter().copied().collect(),
+        }
+    }
+
+    /// Someone who never said hello is from before there was a handshake, which is also from
+    /// before any of these. All they know is joining, leaving, renaming and talking.
+    pub fn legacy() -> Self {
+        Self {
+            version: 0,
+            supported: BTreeSet::new(),
+        }
+    }
+
+    /// Reads a `Hello` or `Welcome`. Capabilities we've never heard of are left out, since there's
+    /// nothing we could do with them anyway.
+    pub fn from_message(message: &SerializableMessage) -> Self {
+        Self {
+            version: message.get_protocol_version().unwrap_or_default(),
+            supported: message
+                .get_capabilities()
+                .iter()
+                .filter_map(|name| Capability::from_name(name))
+                .collect(),
+        }
+    }
+
+    /// A `Hello` or `Welcome` (going by `message_type`) that says all this.
+    pub fn to_message(&self, message_type: SerializableMessageType) -> SerializableMessage {
+        SerializableMessage::new(User::new_static(), message_type, String::new())
+            .set_protocol_version(self.version)
+            .set_capabilities(
+                self.supported
+                    .iter()
+                    .map(|capability| capability.get_name().to_owned())
+                    .collect(),
+            )
+    }
+
+    pub fn get_version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.supported.contains(&capability)
+    }
+
+    /// The capability `message` needs that's missing, if there is one.
+    pub fn missing_for(&self, message: &SerializableMessage) -> Option<Capability> {
+        Capability::needed_for(&message.get_message_type())
+            .filter(|&capability| !self.supports(capability))
+    }
+
+    /// Whether `message` would make any sense to whoever has these capabilities.
+    pub fn understands(&self, message: &SerializableMessage) -> bool {
+        self.missing_for(message).is_none()
+    }
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self::legacy()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hello_round_trips() {
+        let hello = Capabilities::ours().to_message(SerializableMessageType::Hello);
+        let json = serde_json::to_string(&hello).expect("Failed to serialize");
+        let hello = serde_json::from_str(&json).expect("Failed to deserialize");
+        assert_eq!(Capabilities::from_message(&hello), Capabilities::ours());
+    }
+
+    #[test]
+    fn unknown_capabilities_are_ignored() {
+        let hello: SerializableMessage = serde_json::from_str(
+            r#"{"user":{},"message_type":"Hello","content":"","protocol_version":7,"capabilities":["acks","teleportation"]}"#,
+        )
+        .expect("Failed to deserialize");
+        let capabilities = Capabilities::from_message(&hello);
+
+        assert_eq!(capabilities.get_version(), 7);
+        assert!(capabilities.supports(Capability::Acks), "acks went missing");
+        assert!(
+            !capabilities.supports(Capability::Typing),
+            "typing came from nowhere"
+        );
+    }
+
+    #[test]
+    fn core_messages_are_always_understood() {
+        let nothing = Capabilities::from_message(&SerializableMessage::new(
+            User::new_static(),
+            SerializableMessageType::Hello,
+            String::new(),
+        ));
+        let message = |message_type| {
+            SerializableMessage::new(User::new_static(), message_type, String::new())
+        };
+
+        assert!(nothing.understands(&message(SerializableMessageType::Text)));
+        assert!(nothing.understands(&message(SerializableMessageType::Error)));
+        assert_eq!(
+            nothing.missing_for(&message(SerializableMessageType::Edit)),
+            Some(Capability::Edits)
+        );
+        assert!(Capabilities::legacy().understands(&message(SerializableMessageType::Text)));
+        assert!(!Capabilities::legacy().understands(&message(SerializableMessageType::Ack)));
+    }
+}
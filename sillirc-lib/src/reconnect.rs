@@ -0,0 +1,93 @@
+use std::time::Duration;
+
+use uuid::Uuid;
+
+/// How hard the networker tries to get (back) in touch with the server.
+///
+/// Each failed attempt waits twice as long as the last, up to `max_delay`, with a bit of
+/// randomness so everyone who got dropped at once doesn't come knocking at once too.
+#[derive(Clone, Debug)]
+pub struct ReconnectPolicy {
+    initial_delay: Duration,
+    max_delay: Duration,
+    /// How much of each delay can be randomly knocked off, from 0 (none) to 1 (all of it).
+    jitter: f64,
+    /// Failed attempts in a row before giving up, or `None` to keep trying forever.
+    max_attempts: Option<u32>,
+    /// How long a connection has to stay up to count as having worked. One that drops sooner is
+    /// a failed attempt like any other, so a server that hangs up straight away gets backed off
+    /// from too.
+    min_uptime: Duration,
+}
+
+impl ReconnectPolicy {
+    pub fn new() -> Self {
+        Self {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            jitter: 0.5,
+            max_attempts: None,
+            min_uptime: Duration::from_secs(5),
+        }
+    }
+
+    pub fn set_initial_delay(self, initial_delay: Duration) -> Self {
+        Self {
+            initial_delay,
+            ..self
+        }
+    }
+
+    pub fn set_max_delay(self, max_delay: Duration) -> Self {
+        Self { max_delay, ..self }
+    }
+
+    pub fn set_jitter(self, jitter: f64) -> Self {
+        Self {
+            jitter: jitter.clamp(0.0, 1.0),
+            ..self
+        }
+    }
+
+    pub fn set_max_attempts(self, max_attempts: Option<u32>) -> Self {
+        Self {
+            max_attempts,
+            ..self
+        }
+    }
+
+    pub fn set_min_uptime(self, min_uptime: Duration) -> Self {
+        Self { min_uptime, ..self }
+    }
+
+    /// Whether a connection that was up for `uptime` dropped too soon to count as having worked.
+    pub fn dropped_too_soon(&self, uptime: Duration) -> bool {
+        uptime < self.min_uptime
+    }
+
+    /// Whether it's time to stop after `failures` failed attempts in a row.
+    pub fn gives_up_after(&self, failures: u32) -> bool {
+        self.max_attempts.is_some_and(|max| failures >= max)
+    }
+
+    /// How long to wait after `failures` failed attempts in a row.
+    pub fn delay(&self, failures: u32) -> Duration {
+        let backoff = self
+            .initial_delay
+            .saturating_mul(2_u32.saturating_pow(failures.saturating_sub(1)))
+            .min(self.max_delay);
+
+        // A v4 UUID is just a pile of random bytes, which saves pulling in a whole crate for it.
+        let bytes = Uuid::new_v4().into_bytes();
+        let random = f64::from(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            / f64::from(u32::MAX);
+
+        backoff.mul_f64(1.0 - self.jitter * random)
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
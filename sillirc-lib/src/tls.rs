@@ -0,0 +1,290 @@
+use std::env;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{self, WebPkiSupportedAlgorithms};
+use rustls::pki_types::pem::PemObject as _;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{
+    CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+
+/// Which certificates to believe when connecting to a `wss://` server. Plain `ws://` doesn't care.
+///
+/// By default that's the usual public certificate authorities. Extra roots can go on top of those
+/// for a server with its own CA, or specific certificates can be pinned instead, which is the way
+/// to go for a self-signed one.
+#[derive(Clone, Debug)]
+pub struct TlsOptions {
+    roots: Vec<CertificateDer<'static>>,
+    /// If there are any, these are the only certificates the server's allowed to have.
+    pinned: Vec<CertificateDer<'static>>,
+}
+
+impl TlsOptions {
+    pub fn new() -> Self {
+        Self {
+            roots: Vec::new(),
+            pinned: Vec::new(),
+        }
+    }
+
+    /// Trusts anything signed by `roots`, as well as the public CAs.
+    pub fn add_roots(self, roots: impl IntoIterator<Item = CertificateDer<'static>>) -> Self {
+        Self {
+            roots: self.roots.into_iter().chain(roots).collect(),
+            ..self
+        }
+    }
+
+    /// Only trusts a server showing exactly one of `pinned`, and nothing else. Its name isn't
+    /// checked either, since self-signed certificates are usually made for the wrong one anyway.
+    pub fn pin(self, pinned: impl IntoIterator<Item = CertificateDer<'static>>) -> Self {
+        Self {
+            pinned: self.pinned.into_iter().chain(pinned).collect(),
+            ..self
+        }
+    }
+
+    /// Picks up PEM files from the `SILLIRC_CA` environment variable (trusted like `add_roots`) and
+    /// `SILLIRC_PIN` (pinned like `pin`), for whichever of them are set.
+    ///
+    /// # Errors
+    /// If one's set, but its file can't be read.
+    pub fn from_env() -> io::Result<Self> {
+        let read = |variable| match env::var(variable) {
+            Ok(path) => read_certificates(path)
+                .map_err(|err| io::Error::new(err.kind(), format!("{variable}: {err}"))),
+            Err(_) => Ok(Vec::new()),
+        };
+        Ok(Self::new()
+            .add_roots(read("SILLIRC_CA")?)
+            .pin(read("SILLIRC_PIN")?))
+    }
+
+    pub fn client_config(&self) -> Arc<ClientConfig> {
+        let provider = Arc::new(crypto::ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .expect("ring supports the default protocol versions");
+
+        let config = if self.pinned.is_empty() {
+            let mut store = RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            };
+            // One bad certificate shouldn't stop the rest from working.
+            store.add_parsable_certificates(self.roots.iter().cloned());
+            builder.with_root_certificates(store)
+        } else {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(PinnedVerifier {
+                    pinned: self.pinned.clone(),
+                    algorithms: provider.signature_verification_algorithms,
+                }))
+        };
+
+        Arc::new(config.with_no_client_auth())
+    }
+}
+
+impl Default for TlsOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reads every certificate out of the PEM file at `path`.
+///
+/// # Errors
+/// If the file can't be read, or has no certificates in it.
+pub fn read_certificates(path: impl AsRef<Path>) -> io::Result<Vec<CertificateDer<'static>>> {
+    let path = path.as_ref();
+    let certificates = CertificateDer::pem_file_iter(path)
+        .and_then(Iterator::collect::<Result<Vec<_>, _>>)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+    if certificates.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("no certificates in {}", path.display()),
+        ));
+    }
+    Ok(certificates)
+}
+
+/// Accepts the pinned certificates and nothing else, though it still makes sure the server
+/// actually has the key for one.
+struct PinnedVerifier {
+    pinned: Vec<CertificateDer<'static>>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl fmt::Debug for PinnedVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PinnedVerifier")
+            .field("pinned", &self.pinned.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if self.pinned.iter().any(|pinned| pinned == end_entity) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt as _;
+    use rcgen::{CertifiedKey, KeyPair};
+    use rustls::ServerConfig;
+    use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    use super::*;
+    use crate::networker::{ConnectOptions, ConnectionEvent, Event, Networker};
+    use crate::reconnect::ReconnectPolicy;
+
+    fn certificate() -> CertifiedKey<KeyPair> {
+        rcgen::generate_simple_self_signed(vec![String::from("localhost")])
+            .expect("Failed to make a certificate")
+    }
+
+    /// A server on localhost that takes WebSocket connections over TLS, and does nothing with them.
+    async fn serve(certificate: &CertifiedKey<KeyPair>) -> String {
+        let key = PrivatePkcs8KeyDer::from(certificate.signing_key.serialize_der());
+        let config =
+            ServerConfig::builder_with_provider(Arc::new(crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .expect("Failed to pick protocol versions")
+                .with_no_client_auth()
+                .with_single_cert(
+                    vec![certificate.cert.der().clone()],
+                    PrivateKeyDer::from(key),
+                )
+                .expect("Failed to use the certificate");
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Failed to bind");
+        let port = listener.local_addr().expect("Failed to get port").port();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let Ok(stream) = acceptor.accept(stream).await else {
+                        return;
+                    };
+                    let Ok(mut ws_stream) = tokio_tungstenite::accept_async(stream).await else {
+                        return;
+                    };
+                    while ws_stream.next().await.is_some() {}
+                });
+            }
+        });
+
+        format!("wss://localhost:{port}")
+    }
+
+    /// Whether `tls` gets a connection to `url`, with only the one try.
+    async fn connects(url: &str, tls: TlsOptions) -> bool {
+        let policy = ReconnectPolicy::new().set_max_attempts(Some(1));
+        let options = ConnectOptions::new().set_policy(policy).set_tls(tls);
+        let (_networker, mut events) = Networker::connect(url, options);
+
+        while let Some(event) = events.next().await {
+            match event {
+                Event::Connection(ConnectionEvent::Connected) => return true,
+                Event::Connection(ConnectionEvent::GaveUp) => return false,
+                _ => {}
+            }
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn self_signed_is_refused_by_default() {
+        let url = serve(&certificate()).await;
+        assert!(!connects(&url, TlsOptions::new()).await, "connected anyway");
+    }
+
+    #[tokio::test]
+    async fn custom_root_is_trusted() {
+        let certificate = certificate();
+        let url = serve(&certificate).await;
+        let tls = TlsOptions::new().add_roots([certificate.cert.der().clone()]);
+        assert!(connects(&url, tls).await, "didn't connect");
+    }
+
+    #[tokio::test]
+    async fn pinned_certificate_is_trusted() {
+        let certificate = certificate();
+        let url = serve(&certificate).await;
+        let tls = TlsOptions::new().pin([certificate.cert.der().clone()]);
+        assert!(connects(&url, tls).await, "didn't connect");
+    }
+
+    #[tokio::test]
+    async fn other_certificate_is_refused_when_pinned() {
+        let url = serve(&certificate()).await;
+        let tls = TlsOptions::new().pin([certificate().cert.der().clone()]);
+        assert!(!connects(&url, tls).await, "connected anyway");
+    }
+
+    #[test]
+    fn reads_certificates_from_pem() {
+        let certificate = certificate();
+        let path = std::env::temp_dir().join(format!("sillirc-tls-{}.pem", std::process::id()));
+        std::fs::write(&path, certificate.cert.pem()).expect("Failed to write certificate");
+
+        let read = read_certificates(&path);
+        std::fs::remove_file(&path).expect("Failed to clean up");
+        assert_eq!(
+            read.expect("Failed to read certificate"),
+            vec![certificate.cert.der().clone()],
+            "read the wrong certificate"
+        );
+    }
+}
@@ -1,65 +1,355 @@
 // based on https://github.com/snapview/tokio-tungstenite/blob/master/examples/client.rs
 
 use colored::Colorize as _;
-use sillirc_lib::networker::{Networker, SerializableMessage, SerializableMessageType};
+use futures::StreamExt as _;
+use sillirc_lib::codec::Codec;
+use sillirc_lib::delivery::{Delivery, DeliveryError, SendError};
+use sillirc_lib::networker::{
+    ConnectOptions, ConnectionEvent, DEFAULT_ROOM, Event, Networker, SerializableMessage,
+    SerializableMessageType,
+};
+use sillirc_lib::tls::TlsOptions;
 use sillirc_lib::user::User;
 use std::env;
-use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+use tokio::io::{AsyncBufReadExt as _, AsyncWriteExt as _, BufReader, Stdout};
 
-static mut USER: User = User::new_static();
+/// Reactions squished down to something like ` [👍2 🎉1]`, or nothing if there aren't any.
+fn format_reactions(message: &SerializableMessage) -> String {
+    let reactions = message.get_reactions();
+    if reactions.is_empty() {
+        return String::new();
+    }
+
+    let tally: Vec<String> = reactions
+        .iter()
+        .map(|(emoji, count)| format!("{emoji}{count}"))
+        .collect();
+    format!(" [{}]", tally.join(" "))
+}
+
+/// The `[HH:MM #id] ` in front of everything, if the server stamped the message.
+fn format_stamp(message: &SerializableMessage) -> String {
+    match (message.get_utc_time(), message.get_id()) {
+        (Some(time), Some(id)) => format!("[{time} #{id}] "),
+        _ => String::new(),
+    }
+}
+
+/// Everyone in a roster, in their own colors.
+fn format_roster(message: &SerializableMessage) -> String {
+    let names: Vec<String> = message
+        .get_roster()
+        .iter()
+        .map(|user| {
+            let (r, g, b) = user.get_color();
+            user.get_username().truecolor(r, g, b).to_string()
+        })
+        .collect();
+    names.join(", ")
+}
+
+/// Our own messages are already on screen from when we typed them, but the server only tells us
+/// about our own rename once it's happened, so that's when we start going by the new name. Our own
+/// join coming back means the server's let us in.
+fn own_message(user: &mut User, joined: &mut bool, message: &SerializableMessage) {
+    match message.get_message_type() {
+        SerializableMessageType::Join => {
+            *joined = true;
+            return;
+        }
+        SerializableMessageType::Rename => {}
+        _ => return,
+    }
 
-async fn print_message(message: SerializableMessage) {
     let content = message.get_content();
-    let message_user = message.get_user();
-    let (r, g, b) = message_user.get_color();
+    let (r, g, b) = user.get_color();
+    println!(
+        "{} {}",
+        "you are now known as".dimmed(),
+        content.truecolor(r, g, b)
+    );
+    *user = user.clone().set_username(content);
+}
 
-    let user;
-    #[expect(unsafe_code, static_mut_refs)]
-    // SAFETY:
-    // Yeah I could avoid doing this but that's lame
-    unsafe {
-        user = USER.clone();
+/// Things the server is telling us, rather than passing on from someone else.
+fn print_server_message(message: &SerializableMessage) {
+    let content = message.get_content();
+    match message.get_message_type() {
+        SerializableMessageType::Roster => {
+            println!("{} {}", "online:".dimmed(), format_roster(message));
+        }
+        SerializableMessageType::Error => {
+            let code = message
+                .get_error()
+                .map(|code| format!(" ({code:?})"))
+                .unwrap_or_default();
+            println!("{}{}", format!("error: {content}").red(), code.dimmed());
+        }
+        SerializableMessageType::LoggedIn => {
+            println!("{}", format!("logged in as {content}").green());
+        }
+        _ => {}
     }
+}
+
+fn print_message(user: &mut User, joined: &mut bool, message: &SerializableMessage) {
+    let content = message.get_content();
+    let message_user = message.get_user();
+    let (r, g, b) = message_user.get_color();
 
     if user.get_username() == message_user.get_username()
         && user.get_color() == message_user.get_color()
     {
+        own_message(user, joined, message);
         return;
     }
 
+    let stamp = format_stamp(message);
+    let room = if message.is_history() {
+        format!("{stamp}#{} (history)", message.get_room()).dimmed()
+    } else {
+        format!("{stamp}#{}", message.get_room()).dimmed()
+    };
+
     match message.get_message_type() {
         SerializableMessageType::Join => {
             println!(
-                "{} has joined the chat.",
+                "{room} {} has joined the chat.",
                 message_user.get_username().truecolor(r, g, b)
             );
         }
         SerializableMessageType::Leave => {
             println!(
-                "{} has left the chat",
+                "{room} {} has left the chat",
                 message_user.get_username().truecolor(r, g, b)
             );
         }
         SerializableMessageType::Rename => {
+            // Renaming to the same name is how colors get changed.
+            let change = if content == message_user.get_username() {
+                String::from("their color")
+            } else {
+                format!("their name to {}", content.truecolor(r, g, b))
+            };
             println!(
-                "{} changed their name to {}",
-                message_user.get_username().truecolor(r, g, b),
-                content.truecolor(r, g, b)
+                "{}{} changed {change}",
+                stamp.dimmed(),
+                message_user.get_username().truecolor(r, g, b)
             );
         }
         SerializableMessageType::Text => {
+            if let Some(reply_to) = message.get_reply_to() {
+                println!("{}", format!("  ↳ replying to #{reply_to}").dimmed());
+            }
             println!(
-                "{}: {}",
+                "{room} {}: {}{}",
                 message_user.get_username().truecolor(r, g, b),
+                content,
+                format_reactions(message).dimmed()
+            );
+        }
+        // Nobody can see you typing in a terminal anyway, and there's no member list to keep up
+        // to date. `/who` is there for that.
+        SerializableMessageType::Typing
+        | SerializableMessageType::Who
+        | SerializableMessageType::Online
+        | SerializableMessageType::Offline
+        | SerializableMessageType::Register
+        | SerializableMessageType::Login
+        | SerializableMessageType::Ack
+        | SerializableMessageType::Hello
+        | SerializableMessageType::Welcome => {}
+        SerializableMessageType::Roster
+        | SerializableMessageType::Error
+        | SerializableMessageType::LoggedIn => print_server_message(message),
+        SerializableMessageType::Reaction => {
+            println!(
+                "{room} {} reacted {} to #{}{}",
+                message_user.get_username().truecolor(r, g, b),
+                content,
+                message.get_target().unwrap_or_default(),
+                format_reactions(message).dimmed()
+            );
+        }
+        SerializableMessageType::Edit => {
+            println!(
+                "{room} {} edited #{}: {}",
+                message_user.get_username().truecolor(r, g, b),
+                message.get_target().unwrap_or_default(),
                 content
             );
         }
+        SerializableMessageType::Delete => {
+            println!(
+                "{room} {} deleted #{}",
+                message_user.get_username().truecolor(r, g, b),
+                message.get_target().unwrap_or_default()
+            );
+        }
+        SerializableMessageType::Direct => {
+            println!(
+                "{}{} {} → {}: {}",
+                stamp.dimmed(),
+                "[dm]".magenta(),
+                message_user.get_username().truecolor(r, g, b),
+                message.get_recipient().unwrap_or_default(),
+                content.italic()
+            );
+        }
+    }
+}
+
+/// Turns a line of input into a message, handling the IRC style commands along the way.
+fn parse_input(user: &User, room: &mut String, input: &str) -> Option<SerializableMessage> {
+    let (command, args) = input.split_once(' ').unwrap_or((input, ""));
+    let args = args.trim();
+
+    let in_room = |message_type, content: &str, room: &str| {
+        SerializableMessage::new(user.clone(), message_type, content.to_owned())
+            .set_room(room.to_owned())
+    };
+
+    match command {
+        "/join" if !args.is_empty() => {
+            *room = args.trim_start_matches('#').to_owned();
+            Some(in_room(SerializableMessageType::Join, "", room))
+        }
+        "/leave" if room != DEFAULT_ROOM => {
+            let message = in_room(SerializableMessageType::Leave, "", room);
+            *room = String::from(DEFAULT_ROOM);
+            Some(message)
+        }
+        "/msg" => {
+            let (recipient, content) = args.split_once(' ')?;
+            Some(SerializableMessage::new_direct(
+                user.clone(),
+                recipient.to_owned(),
+                content.to_owned(),
+            ))
+        }
+        "/edit" => {
+            let (id, content) = args.split_once(' ')?;
+            Some(in_room(SerializableMessageType::Edit, content, room).set_target(id.parse().ok()?))
+        }
+        "/reply" => {
+            let (id, content) = args.split_once(' ')?;
+            Some(
+                in_room(SerializableMessageType::Text, content, room)
+                    .set_reply_to(id.parse().ok()?),
+            )
+        }
+        "/react" => {
+            let (id, emoji) = args.split_once(' ')?;
+            Some(
+                in_room(SerializableMessageType::Reaction, emoji.trim(), room)
+                    .set_target(id.parse().ok()?),
+            )
+        }
+        "/nick" if !args.is_empty() => Some(in_room(SerializableMessageType::Rename, args, room)),
+        "/who" => Some(in_room(SerializableMessageType::Who, "", room)),
+        "/delete" => {
+            Some(in_room(SerializableMessageType::Delete, "", room).set_target(args.parse().ok()?))
+        }
+        _ => Some(in_room(SerializableMessageType::Text, input, room)),
+    }
+}
+
+fn print_connection_event(event: &ConnectionEvent) {
+    if event.is_connected() {
+        println!("{}", event.to_string().green());
+    } else {
+        println!("{}", event.to_string().yellow());
+    }
+}
+
+/// Whatever it is, it's from a newer version than us. JSON's at least readable, so that gets
+/// shown as is.
+fn print_unknown(codec: Codec, raw: &[u8]) {
+    let shown = if codec == Codec::Json {
+        String::from_utf8_lossy(raw).into_owned()
+    } else {
+        format!("{} bytes of {codec}", raw.len())
+    };
+    println!(
+        "{}",
+        format!("got something we don't understand: {shown}").dimmed()
+    );
+}
+
+async fn print_prompt(stdout: &mut Stdout, user: &User, room: &str) {
+    let (r, g, b) = user.get_color();
+    stdout
+        .write_all(
+            format!(
+                "{} {}: ",
+                format!("#{room}").dimmed(),
+                user.get_username().truecolor(r, g, b)
+            )
+            .as_bytes(),
+        )
+        .await
+        .expect("Failed to write username");
+    stdout.flush().await.expect("Failed to flush stdout");
+}
+
+/// Complains if a message doesn't make it. The server already tells us about the ones it turns
+/// down, so it's just the ones that get lost along the way.
+fn watch_delivery(sent: Result<Delivery, SendError>) {
+    match sent {
+        Ok(delivery) => {
+            tokio::spawn(async move {
+                if let Err(err @ DeliveryError::Lost) = delivery.await {
+                    println!("{}", format!("didn't send: {err}").red());
+                }
+            });
+        }
+        Err(err) => println!("{}", format!("couldn't send: {err}").red()),
+    }
+}
+
+fn join(user: &User, room: &str) -> SerializableMessage {
+    SerializableMessage::new(user.clone(), SerializableMessageType::Join, String::new())
+        .set_room(room.to_owned())
+}
+
+/// Sends off whatever was typed in.
+async fn handle_input(
+    nw: &mut Networker,
+    user: &mut User,
+    joined: bool,
+    room: &mut String,
+    input: &str,
+) {
+    // Logging in doesn't count for the name we were already turned away with, so have another
+    // go at joining once it's done.
+    let (command, args) = input.split_once(' ').unwrap_or_default();
+    if matches!(command, "/login" | "/register") && !args.is_empty() {
+        if command == "/login" {
+            watch_delivery(nw.login(user.clone(), args.to_owned()).await);
+        } else {
+            watch_delivery(nw.register(user.clone(), args.to_owned()).await);
+        }
+        watch_delivery(nw.send(join(user, room)).await);
+        return;
+    }
+
+    // There's nobody to rename until the server's let us in, so the new name has a go at joining
+    // instead.
+    let nickname = args.trim();
+    if command == "/nick" && !nickname.is_empty() && !joined {
+        *user = user.clone().set_username(nickname.to_owned());
+        watch_delivery(nw.send(join(user, room)).await);
+        return;
+    }
+
+    if let Some(message) = parse_input(user, room, input) {
+        watch_delivery(nw.send(message).await);
     }
 }
 
 #[tokio::main]
 async fn main() {
-    let mut stdin = tokio::io::stdin();
+    let mut stdin = BufReader::new(tokio::io::stdin()).lines();
     let mut stdout = tokio::io::stdout();
 
     let addr = env::args()
@@ -70,39 +360,35 @@ async fn main() {
         .nth(1)
         .unwrap_or_else(|| String::from("Anonymouse"));
 
-    let user = User::new(username);
-    #[expect(unsafe_code)]
-    // SAFETY:
-    // Yeah I could avoid doing this but that's lame
-    unsafe {
-        USER = user.clone();
-    }
+    let mut user = User::new(username);
+    let tls = TlsOptions::from_env().expect("Failed to read certificates");
+    let (mut nw, mut events) = Networker::connect(&addr, ConnectOptions::new().set_tls(tls));
 
-    let mut nw = Networker::new(&addr, print_message).await;
+    let mut room = String::from(DEFAULT_ROOM);
+    let mut joined = false;
 
-    let (r, g, b) = user.get_color();
+    // Joining straight away means we find out now if someone else already has our name.
+    watch_delivery(nw.send(join(&user, &room)).await);
+
+    print_prompt(&mut stdout, &user, &room).await;
 
     loop {
-        stdout
-            .write_all(format!("{}: ", user.get_username().truecolor(r, g, b)).as_bytes())
-            .await
-            .expect("Failed to write username");
-        stdout.flush().await.expect("Failed to flush stdout");
-
-        let mut buf = vec![0; 1024];
-        let n = match stdin.read(&mut buf).await {
-            Err(_) | Ok(0) => break,
-            Ok(n) => n,
-        };
-        buf.truncate(n);
-        let Ok(text_content) = String::from_utf8(buf) else {
-            continue;
-        };
-        nw.send(SerializableMessage::new(
-            user.clone(),
-            SerializableMessageType::Text,
-            text_content.replace('\n', ""),
-        ))
-        .await;
+        tokio::select! {
+            line = stdin.next_line() => {
+                let text_content = match line {
+                    Ok(Some(text_content)) => text_content,
+                    Ok(None) => break,
+                    Err(_) => continue,
+                };
+                handle_input(&mut nw, &mut user, joined, &mut room, &text_content).await;
+                print_prompt(&mut stdout, &user, &room).await;
+            }
+            event = events.next() => match event {
+                Some(Event::Message(message)) => print_message(&mut user, &mut joined, &message),
+                Some(Event::Connection(event)) => print_connection_event(&event),
+                Some(Event::Unknown { codec, raw }) => print_unknown(codec, &raw),
+                None => break,
+            },
+        }
     }
 }
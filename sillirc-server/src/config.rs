@@ -0,0 +1,83 @@
+use std::{env, path::PathBuf};
+
+use crate::history::HISTORY_LENGTH;
+
+/// Where the server keeps the messages it has seen.
+pub enum StorageConfig {
+    /// Nothing is written anywhere, so everything is gone after a restart.
+    Memory,
+    /// One JSON record per line, only ever appended to.
+    Log(PathBuf),
+    /// An embedded `SQLite` database.
+    Sqlite(PathBuf),
+}
+
+impl StorageConfig {
+    /// Parses `memory`, `log:<path>` or `sqlite:<path>`.
+    fn parse(spec: &str) -> Result<Self, String> {
+        match spec.split_once(':') {
+            None if spec == "memory" => Ok(Self::Memory),
+            Some(("log", path)) if !path.is_empty() => Ok(Self::Log(PathBuf::from(path))),
+            Some(("sqlite", path)) if !path.is_empty() => Ok(Self::Sqlite(PathBuf::from(path))),
+            _ => Err(format!(
+                "unknown storage \"{spec}\", expected memory, log:<path> or sqlite:<path>"
+            )),
+        }
+    }
+}
+
+/// The certificate chain and private key to serve `wss://` with, both as PEM files.
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+pub struct Config {
+    pub addr: String,
+    pub storage: StorageConfig,
+    pub history_length: usize,
+    /// Plain `ws://` if there isn't any.
+    pub tls: Option<TlsConfig>,
+}
+
+impl Config {
+    /// The address comes from the first argument, everything else from `SILLIRC_*` environment
+    /// variables.
+    pub fn from_env() -> Result<Self, String> {
+        let addr = env::args()
+            .nth(1)
+            .unwrap_or_else(|| String::from("127.0.0.1:9238"));
+
+        let storage = match env::var("SILLIRC_STORAGE") {
+            Ok(spec) => StorageConfig::parse(&spec)?,
+            Err(_) => StorageConfig::Memory,
+        };
+
+        let history_length = match env::var("SILLIRC_HISTORY_LENGTH") {
+            Ok(length) => length
+                .parse()
+                .map_err(|err| format!("invalid SILLIRC_HISTORY_LENGTH \"{length}\": {err}"))?,
+            Err(_) => HISTORY_LENGTH,
+        };
+
+        let tls = match (env::var("SILLIRC_TLS_CERT"), env::var("SILLIRC_TLS_KEY")) {
+            (Ok(cert), Ok(key)) => Some(TlsConfig {
+                cert: PathBuf::from(cert),
+                key: PathBuf::from(key),
+            }),
+            (Err(_), Err(_)) => None,
+            _ => {
+                return Err(String::from(
+                    "SILLIRC_TLS_CERT and SILLIRC_TLS_KEY have to be set together",
+                ));
+            }
+        };
+
+        Ok(Self {
+            addr,
+            storage,
+            history_length,
+            tls,
+        })
+    }
+}
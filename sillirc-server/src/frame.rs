@@ -0,0 +1,191 @@
+use tokio_tungstenite::tungstenite::{
+    Error as WsError,
+    error::ProtocolError,
+    protocol::{CloseFrame, Message, frame::coding::CloseCode},
+};
+
+use sillirc_lib::codec::Codec;
+use sillirc_lib::networker::SerializableMessage;
+
+/// What to do about a frame that came in from a client.
+pub enum Frame {
+    /// An actual message, along with what it looks like as JSON (for the logs).
+    Message(String, Box<SerializableMessage>),
+    /// Valid text that isn't a message. The client gets told, but they get to stay.
+    Malformed(String),
+    /// Something that isn't even text when it's meant to be, so there's no point carrying on with
+    /// this client.
+    Close(CloseFrame),
+    /// Pings, pongs and close frames, which tungstenite deals with for us.
+    Skip,
+}
+
+fn close_frame(code: CloseCode, reason: &str) -> CloseFrame {
+    CloseFrame {
+        code,
+        reason: reason.into(),
+    }
+}
+
+/// Works out what a frame is without trusting anything about it. Text frames are always JSON, and
+/// binary ones are in whatever `codec` the connection agreed on.
+pub fn decode(codec: Codec, frame: &Message) -> Frame {
+    let text = match frame {
+        Message::Text(text) => text.as_str(),
+        Message::Binary(bytes) if codec != Codec::Json => {
+            return match codec.decode(bytes) {
+                Ok(message) => Frame::Message(
+                    serde_json::to_string(&message).unwrap_or_default(),
+                    Box::new(message),
+                ),
+                Err(err) => Frame::Malformed(err.to_string()),
+            };
+        }
+        Message::Binary(bytes) => match str::from_utf8(bytes) {
+            Ok(text) => text,
+            Err(_not_utf8) => {
+                return Frame::Close(close_frame(CloseCode::Invalid, "Messages have to be UTF-8"));
+            }
+        },
+        Message::Ping(_) | Message::Pong(_) | Message::Close(_) | Message::Frame(_) => {
+            return Frame::Skip;
+        }
+    };
+
+    match serde_json::from_str(text) {
+        Ok(message) => Frame::Message(text.to_owned(), Box::new(message)),
+        Err(err) => Frame::Malformed(err.to_string()),
+    }
+}
+
+/// The close frame to send when reading from a client failed, if they're still around to get it.
+pub fn close_for(error: &WsError) -> Option<CloseFrame> {
+    match error {
+        // They've gone without saying goodbye, so they won't be hearing one either.
+        WsError::Protocol(ProtocolError::ResetWithoutClosingHandshake) => None,
+        WsError::Utf8(_) => Some(close_frame(CloseCode::Invalid, "Messages have to be UTF-8")),
+        WsError::Capacity(_) => Some(close_frame(CloseCode::Size, "That message was too big")),
+        WsError::Protocol(_) => Some(close_frame(CloseCode::Protocol, "That wasn't WebSocket")),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use sillirc_lib::networker::SerializableMessageType;
+    use tokio_tungstenite::tungstenite::error::CapacityError;
+
+    const JOIN: &str = r#"{"user":{"username":"alice","color":[1,2,3],"uuid":"00000000-0000-0000-0000-000000000001"},"message_type":"Join","content":""}"#;
+
+    #[test]
+    fn messages_decode_from_text_and_binary() {
+        for frame in [
+            Message::text(JOIN),
+            Message::binary(JOIN.as_bytes().to_vec()),
+        ] {
+            let Frame::Message(text, message) = decode(Codec::Json, &frame) else {
+                panic!("{frame:?} should have been a message");
+            };
+            assert_eq!(text, JOIN);
+            assert!(matches!(
+                message.get_message_type(),
+                SerializableMessageType::Join
+            ));
+        }
+    }
+
+    #[test]
+    fn invalid_json_is_malformed() {
+        for garbage in ["", "hello", "{", "[1, 2, 3]", r#"{"message_type":"Text"}"#] {
+            assert!(
+                matches!(
+                    decode(Codec::Json, &Message::text(garbage)),
+                    Frame::Malformed(_)
+                ),
+                "{garbage:?} should have been malformed"
+            );
+            assert!(matches!(
+                decode(Codec::Json, &Message::binary(garbage.as_bytes().to_vec())),
+                Frame::Malformed(_)
+            ));
+        }
+    }
+
+    #[test]
+    fn binary_blobs_close_the_connection() {
+        let Frame::Close(close) =
+            decode(Codec::Json, &Message::binary(vec![0xff, 0xfe, 0x00, 0x80]))
+        else {
+            panic!("a blob should close the connection");
+        };
+        assert_eq!(close.code, CloseCode::Invalid);
+    }
+
+    #[test]
+    fn binary_codecs_decode_binary_frames() {
+        let join: SerializableMessage = serde_json::from_str(JOIN).expect("JOIN is a message");
+
+        for &codec in Codec::ALL {
+            let frame = codec.encode(&join).expect("Failed to encode");
+            let Frame::Message(_, message) = decode(codec, &frame) else {
+                panic!("{codec} should have decoded its own frame");
+            };
+            assert_eq!(message.get_user().get_username(), "alice", "{codec}");
+
+            if codec != Codec::Json {
+                assert!(
+                    matches!(
+                        decode(codec, &Message::binary(vec![0xff, 0xfe, 0x00, 0x80])),
+                        Frame::Malformed(_)
+                    ),
+                    "{codec} should have kept the connection open"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn control_frames_are_skipped() {
+        for frame in [
+            Message::Ping(vec![1, 2, 3].into()),
+            Message::Pong(Vec::new().into()),
+            Message::Close(None),
+            Message::Close(Some(close_frame(CloseCode::Normal, "bye"))),
+        ] {
+            assert!(
+                matches!(decode(Codec::Json, &frame), Frame::Skip),
+                "{frame:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn read_errors_pick_a_close_code() {
+        let code = |error| close_for(&error).map(|close| close.code);
+
+        assert_eq!(
+            code(WsError::Utf8(String::from("bad"))),
+            Some(CloseCode::Invalid)
+        );
+        assert_eq!(
+            code(WsError::Capacity(CapacityError::MessageTooLong {
+                size: 2,
+                max_size: 1
+            })),
+            Some(CloseCode::Size)
+        );
+        assert_eq!(
+            code(WsError::Protocol(ProtocolError::UnmaskedFrameFromClient)),
+            Some(CloseCode::Protocol)
+        );
+        assert_eq!(
+            code(WsError::Protocol(
+                ProtocolError::ResetWithoutClosingHandshake
+            )),
+            None
+        );
+        assert_eq!(code(WsError::ConnectionClosed), None);
+    }
+}
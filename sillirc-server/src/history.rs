@@ -0,0 +1,83 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use sillirc_lib::networker::{SerializableMessage, SerializableMessageType};
+
+pub const HISTORY_LENGTH: usize = 100;
+
+/// The last few messages said in each room, so people who show up late can catch up.
+pub struct History {
+    length: usize,
+    rooms: HashMap<String, VecDeque<SerializableMessage>>,
+}
+
+impl History {
+    pub fn new(length: usize) -> Self {
+        Self {
+            length,
+            rooms: HashMap::new(),
+        }
+    }
+
+    /// Only actual chat lines are worth replaying, comings and goings are left out. Edits and
+    /// deletes are applied to whatever they refer to instead of being kept themselves.
+    pub fn record(&mut self, message: &SerializableMessage) {
+        let room = self.rooms.entry(message.get_room()).or_default();
+
+        match message.get_message_type() {
+            SerializableMessageType::Text => {
+                room.push_back(message.clone().set_history(true));
+                while room.len() > self.length {
+                    room.pop_front();
+                }
+            }
+            SerializableMessageType::Edit => {
+                if let Some(original) = room
+                    .iter_mut()
+                    .find(|original| original.get_id() == message.get_target())
+                {
+                    *original = original
+                        .clone()
+                        .set_content(message.get_content())
+                        .set_edited(true);
+                }
+            }
+            SerializableMessageType::Delete => {
+                room.retain(|original| original.get_id() != message.get_target());
+            }
+            // Reactions are tallied up elsewhere and handed over through `set_reactions`.
+            SerializableMessageType::Join
+            | SerializableMessageType::Leave
+            | SerializableMessageType::Rename
+            | SerializableMessageType::Direct
+            | SerializableMessageType::Reaction
+            | SerializableMessageType::Typing
+            | SerializableMessageType::Who
+            | SerializableMessageType::Roster
+            | SerializableMessageType::Online
+            | SerializableMessageType::Offline
+            | SerializableMessageType::Error
+            | SerializableMessageType::Register
+            | SerializableMessageType::Login
+            | SerializableMessageType::LoggedIn
+            | SerializableMessageType::Ack
+            | SerializableMessageType::Hello
+            | SerializableMessageType::Welcome => {}
+        }
+    }
+
+    pub fn set_reactions(&mut self, room: &str, target: u64, reactions: BTreeMap<String, u32>) {
+        if let Some(original) = self
+            .rooms
+            .get_mut(room)
+            .into_iter()
+            .flatten()
+            .find(|original| original.get_id() == Some(target))
+        {
+            *original = original.clone().set_reactions(reactions);
+        }
+    }
+
+    pub fn replay(&self, room: &str) -> impl Iterator<Item = &SerializableMessage> {
+        self.rooms.get(room).into_iter().flatten()
+    }
+}
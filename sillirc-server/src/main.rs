@@ -1,100 +1,220 @@
 // based on https://github.com/snapview/tokio-tungstenite/blob/master/examples/server.rs
 
 use std::{
-    collections::HashMap,
-    env,
     io::Error as IoError,
     net::SocketAddr,
     sync::{Arc, Mutex},
 };
 
-use futures::channel::mpsc::{UnboundedSender, unbounded};
-use futures::{StreamExt as _, future, pin_mut, stream::TryStreamExt as _};
+use futures::channel::mpsc::unbounded;
+use futures::{StreamExt as _, future, future::Either, pin_mut, stream::TryStreamExt as _};
 
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{TcpListener, TcpStream};
-use tokio_tungstenite::tungstenite::protocol::Message;
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::tungstenite::{
+    handshake::server::{Request, Response},
+    http::header::SEC_WEBSOCKET_PROTOCOL,
+    protocol::{CloseFrame, Message},
+};
 
-type Tx = UnboundedSender<Message>;
-type PeerMap = Arc<Mutex<HashMap<SocketAddr, Tx>>>;
+mod config;
+mod frame;
+mod history;
+mod rate_limit;
+mod state;
+mod storage;
+mod tls;
+
+use crate::config::Config;
+use crate::frame::Frame;
+use crate::state::{Peer, ServerState};
+use crate::storage::Storage;
+use sillirc_lib::codec::Codec;
+use sillirc_lib::networker::{ErrorCode, SerializableMessageType};
+
+type SharedState = Arc<Mutex<ServerState>>;
+
+/// Deals with a single frame from `addr`. An `Err` means it's time to hang up on them.
+async fn handle_frame(
+    state: &SharedState,
+    addr: SocketAddr,
+    codec: Codec,
+    msg: &Message,
+) -> Result<(), CloseFrame> {
+    match frame::decode(codec, msg) {
+        Frame::Message(text, serialized_message) => {
+            // Passwords have no business being in the logs.
+            let message_type = serialized_message.get_message_type();
+            if matches!(
+                message_type,
+                SerializableMessageType::Register | SerializableMessageType::Login
+            ) {
+                println!("Received a {message_type:?} from {addr}");
+            } else {
+                println!("Received a message from {addr}: {text}");
+            }
+
+            let check = state
+                .lock()
+                .expect("State lock failed")
+                .receive(addr, &serialized_message);
+
+            // Passwords are slow to check on purpose, so everyone else carries on in the meantime.
+            // Nothing else from this connection gets looked at until it's done, though.
+            if let Some(check) = check {
+                match tokio::task::spawn_blocking(|| check.run()).await {
+                    Ok(checked) => state
+                        .lock()
+                        .expect("State lock failed")
+                        .finish_account(checked),
+                    Err(err) => println!("Checking a password from {addr} failed: {err}"),
+                }
+            }
+        }
+        Frame::Malformed(err) => {
+            println!("Received something that isn't a message from {addr}: {err}");
+            state.lock().expect("State lock failed").send_error(
+                addr,
+                ErrorCode::Malformed,
+                format!("That wasn't a message: {err}"),
+            );
+        }
+        Frame::Close(close) => return Err(close),
+        Frame::Skip => {}
+    }
 
-use sillirc_lib::networker::SerializableMessage;
+    Ok(())
+}
 
-async fn handle_connection(peer_map: PeerMap, raw_stream: TcpStream, addr: SocketAddr) {
+/// Gets a new connection going, with a TLS handshake first if there's an `acceptor`.
+async fn accept_connection(
+    state: SharedState,
+    raw_stream: TcpStream,
+    addr: SocketAddr,
+    acceptor: Option<TlsAcceptor>,
+) {
     println!("Incoming TCP connection from: {addr}");
 
-    let ws_stream = tokio_tungstenite::accept_async(raw_stream)
-        .await
-        .expect("Error during the websocket handshake occurred");
-    println!("WebSocket connection established: {addr}");
+    let Some(acceptor) = acceptor else {
+        return handle_connection(state, raw_stream, addr).await;
+    };
+    match acceptor.accept(raw_stream).await {
+        Ok(tls_stream) => handle_connection(state, tls_stream, addr).await,
+        Err(err) => println!("TLS handshake with {addr} failed: {err}"),
+    }
+}
+
+async fn handle_connection<S>(state: SharedState, stream: S, addr: SocketAddr)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Clients that don't offer any codecs (which is all of them, from before there were any) get
+    // JSON, and so do ones that only offer codecs we don't know.
+    let mut codec = Codec::Json;
+    // The error type isn't ours to pick.
+    #[expect(clippy::result_large_err)]
+    let negotiate = |request: &Request, mut response: Response| {
+        let offered = request
+            .headers()
+            .get(SEC_WEBSOCKET_PROTOCOL)
+            .and_then(|offer| offer.to_str().ok())
+            .and_then(Codec::negotiate);
+        if let Some(offered) = offered {
+            codec = offered;
+            response.headers_mut().insert(
+                SEC_WEBSOCKET_PROTOCOL,
+                offered
+                    .get_protocol()
+                    .parse()
+                    .expect("Protocol names are valid headers"),
+            );
+        }
+        Ok(response)
+    };
+
+    let ws_stream = match tokio_tungstenite::accept_hdr_async(stream, negotiate).await {
+        Ok(ws_stream) => ws_stream,
+        Err(err) => {
+            println!("WebSocket handshake with {addr} failed: {err}");
+            return;
+        }
+    };
+    println!("WebSocket connection established: {addr} ({codec})");
 
-    // Insert the write part of this peer to the peer map.
+    // Insert the write part of this peer to the peer map, and catch them up on what they missed.
     let (tx, rx) = unbounded();
-    peer_map.lock().expect("Peer lock failed").insert(addr, tx);
+    state
+        .lock()
+        .expect("State lock failed")
+        .add_peer(addr, Peer::new(tx, codec));
 
     let (outgoing, incoming) = ws_stream.split();
 
-    let broadcast_incoming = incoming.try_for_each(|msg| {
-        println!(
-            "Received a message from {}: {}",
-            addr,
-            msg.to_text()
-                .expect("Message couldn't be converted to text")
-        );
-        let peers = peer_map.lock().expect("Peer lock failed");
-
-        // We want to broadcast the message to everyone except ourselves.
-        #[expect(clippy::iter_kv_map)]
-        let broadcast_recipients = peers
-            .iter()
-            // .filter(|(peer_addr, _)| peer_addr != &&addr)
-            .map(|(_, ws_sink)| ws_sink);
-
-        let serialized_message: SerializableMessage =
-            serde_json::from_str(msg.to_text().expect("Failed to convert to text."))
-                .expect("Failed to serialize message");
-
-        let new_message = SerializableMessage::new(
-            serialized_message.get_user().clear_uuid(),
-            serialized_message.get_message_type(),
-            serialized_message.get_content(),
-        );
-
-        let text_new_message =
-            serde_json::to_string(&new_message).expect("Failed to reserialize message");
-
-        for recp in broadcast_recipients {
-            recp.unbounded_send(Message::binary(text_new_message.clone()))
-                .expect("Sending messages failed");
-        }
-
-        future::ok(())
-    });
+    let broadcast_incoming = incoming
+        .map_err(|err| {
+            println!("Failed to read from {addr}: {err}");
+            frame::close_for(&err)
+        })
+        .try_for_each(|msg| {
+            let state = &state;
+            async move { handle_frame(state, addr, codec, &msg).await.map_err(Some) }
+        });
 
     let receive_from_others = rx.map(Ok).forward(outgoing);
 
     pin_mut!(broadcast_incoming, receive_from_others);
-    future::select(broadcast_incoming, receive_from_others).await;
+    if let Either::Left((Err(Some(close)), receive_from_others)) =
+        future::select(broadcast_incoming, receive_from_others).await
+    {
+        println!("Hanging up on {addr}: {close}");
+        state
+            .lock()
+            .expect("State lock failed")
+            .close_peer(addr, close);
+
+        // Dropping them from the state ends their queue, so this finishes once the close frame
+        // is out the door. Whether they heard it or not, there's nothing left to do with them.
+        let _goodbye = receive_from_others.await;
+    }
 
     println!("{} disconnected", &addr);
-    peer_map.lock().expect("Peer lock failed").remove(&addr);
+    state.lock().expect("State lock failed").remove_peer(addr);
 }
 
 #[tokio::main]
 async fn main() -> Result<(), IoError> {
-    let addr = env::args()
-        .nth(1)
-        .unwrap_or_else(|| String::from("127.0.0.1:9238"));
+    let config = Config::from_env().expect("Failed to read config");
+    let addr = config.addr;
+
+    let acceptor = config
+        .tls
+        .as_ref()
+        .map(tls::acceptor)
+        .transpose()
+        .expect("Failed to set up TLS");
 
-    let state = PeerMap::new(Mutex::new(HashMap::new()));
+    let storage = Storage::open(&config.storage).expect("Failed to open storage");
+
+    // Pick up from wherever we left off last time.
+    let state =
+        ServerState::open(config.history_length, storage).expect("Failed to load stored messages");
+    let state = SharedState::new(Mutex::new(state));
 
     // Create the event loop and TCP listener we'll accept connections on.
     let try_socket = TcpListener::bind(&addr).await;
     let listener = try_socket.expect("Failed to bind");
-    println!("Listening on: {addr}");
+    let scheme = if acceptor.is_some() { "wss" } else { "ws" };
+    println!("Listening on: {scheme}://{addr}");
 
     // Let's spawn the handling of each connection in a separate task.
     while let Ok((stream, addr)) = listener.accept().await {
-        tokio::spawn(handle_connection(state.clone(), stream, addr));
+        tokio::spawn(accept_connection(
+            state.clone(),
+            stream,
+            addr,
+            acceptor.clone(),
+        ));
     }
 
     Ok(())
@@ -0,0 +1,35 @@
+use std::time::Instant;
+
+/// How many messages can be sent in one go.
+const BURST: f64 = 20.0;
+/// How quickly that allowance comes back, in messages per second.
+const PER_SECOND: f64 = 5.0;
+
+/// A token bucket per connection, so nobody can drown everyone else out.
+pub struct RateLimiter {
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self {
+            tokens: BURST,
+            last: Instant::now(),
+        }
+    }
+
+    /// Whether another message is allowed right now, using up a bit of the allowance if so.
+    pub fn allow(&mut self) -> bool {
+        let now = Instant::now();
+        let refilled = now.duration_since(self.last).as_secs_f64() * PER_SECOND;
+        self.tokens = (self.tokens + refilled).min(BURST);
+        self.last = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
@@ -0,0 +1,1207 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    net::SocketAddr,
+};
+
+use argon2::{
+    Argon2,
+    password_hash::{PasswordHash, PasswordHasher as _, PasswordVerifier as _, SaltString},
+};
+use futures::channel::mpsc::UnboundedSender;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, Message};
+use unicode_segmentation::UnicodeSegmentation as _;
+
+use sillirc_lib::codec::Codec;
+use sillirc_lib::networker::{
+    DEFAULT_ROOM, ErrorCode, SerializableMessage, SerializableMessageType,
+};
+use sillirc_lib::protocol::{Capabilities, Capability};
+use sillirc_lib::user::User;
+
+use uuid::Uuid;
+
+use crate::history::History;
+use crate::rate_limit::RateLimiter;
+use crate::storage::{Storage, StorageError};
+
+pub type Tx = UnboundedSender<Message>;
+
+/// Anything longer than this isn't a single emoji, even with skin tones and joiners.
+const MAX_EMOJI_LENGTH: usize = 10;
+
+/// How many messages to remember having handled, for spotting ones sent again after a reconnect.
+/// Only what was still waiting on an ack when a connection dropped comes round again, so this is
+/// plenty.
+const REMEMBERED_CLIENT_IDS: usize = 1024;
+
+pub struct Peer {
+    tx: Tx,
+    /// What the connection agreed to speak.
+    codec: Codec,
+    /// What the client said it can do when it said hello, if it did.
+    capabilities: Capabilities,
+    said_hello: bool,
+    /// Which run of the client this is, if it said. Client IDs only mean anything within one.
+    session: Option<Uuid>,
+    rooms: HashSet<String>,
+    user: Option<User>,
+    /// The lowercased nickname this connection logged in to, if any.
+    account: Option<String>,
+    rate_limiter: RateLimiter,
+}
+
+impl Peer {
+    pub fn new(tx: Tx, codec: Codec) -> Self {
+        // Everyone starts out in the default room, so clients that don't know about rooms
+        // still see (and are seen by) each other.
+        Self {
+            tx,
+            codec,
+            capabilities: Capabilities::legacy(),
+            said_hello: false,
+            session: None,
+            rooms: HashSet::from([String::from(DEFAULT_ROOM)]),
+            user: None,
+            account: None,
+            rate_limiter: RateLimiter::new(),
+        }
+    }
+
+    fn shares_room_with(&self, other: &Self) -> bool {
+        !self.rooms.is_disjoint(&other.rooms)
+    }
+
+    /// Anything the client said it can't make sense of is left out, rather than making it guess.
+    fn send(&self, message: &SerializableMessage) {
+        if !self.capabilities.understands(message) {
+            return;
+        }
+
+        match self.codec.encode(message) {
+            Ok(frame) => self.send_frame(frame),
+            Err(err) => println!("Failed to reserialize a message: {err}"),
+        }
+    }
+
+    /// If this fails, the connection is already on its way out, and gets cleaned up from there.
+    fn send_frame(&self, frame: Message) {
+        if let Err(err) = self.tx.unbounded_send(frame) {
+            println!("Couldn't pass a frame along to a connection: {err}");
+        }
+    }
+}
+
+/// Why a message was turned down, as told to whoever sent it.
+type Rejection = (ErrorCode, String);
+
+/// Something that's our fault rather than the client's. The details only go in the server's log.
+fn internal(details: &str) -> Rejection {
+    println!("{details}");
+    (
+        ErrorCode::Internal,
+        String::from("Something went wrong on the server, try again later"),
+    )
+}
+
+fn already_registered(username: &str) -> Rejection {
+    (
+        ErrorCode::AlreadyRegistered,
+        format!("The nickname {username} is already registered"),
+    )
+}
+
+/// A register or login that's only waiting on its password. Hashing is slow on purpose, so it's
+/// done without holding on to the state.
+pub struct PasswordCheck {
+    addr: SocketAddr,
+    client_id: Option<u64>,
+    message: SerializableMessage,
+    /// What the password has to match, for logins. Registering makes a new one instead.
+    password_hash: Option<String>,
+}
+
+impl PasswordCheck {
+    pub fn run(self) -> CheckedPassword {
+        let password = self.message.get_content();
+        let outcome = match &self.password_hash {
+            Some(password_hash) => verify_password(&password, password_hash).map(|()| None),
+            None => hash_password(&password).map(Some),
+        };
+
+        CheckedPassword {
+            addr: self.addr,
+            client_id: self.client_id,
+            message: self.message,
+            outcome,
+        }
+    }
+}
+
+/// How a `PasswordCheck` went, with the new hash if it was registering.
+pub struct CheckedPassword {
+    addr: SocketAddr,
+    client_id: Option<u64>,
+    message: SerializableMessage,
+    outcome: Result<Option<String>, Rejection>,
+}
+
+fn hash_password(password: &str) -> Result<String, Rejection> {
+    // UUIDs are random enough to make a fine salt, and save pulling in a whole RNG for it.
+    let hash_failed = |err| internal(&format!("Failed to hash a password: {err}"));
+    let salt = SaltString::encode_b64(Uuid::new_v4().as_bytes()).map_err(hash_failed)?;
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(hash_failed)?
+        .to_string())
+}
+
+fn verify_password(password: &str, password_hash: &str) -> Result<(), Rejection> {
+    let password_hash = PasswordHash::new(password_hash)
+        .map_err(|err| internal(&format!("Bad password hash: {err}")))?;
+
+    Argon2::default()
+        .verify_password(password.as_bytes(), &password_hash)
+        .map_err(|_wrong| (ErrorCode::WrongPassword, String::from("Wrong password")))
+}
+
+/// Only the parts of `message` a client gets a say in. Everything else, like reactions and
+/// whether it's been edited, is the server's to fill in, so a client can't just make it up.
+fn from_client(message: &SerializableMessage) -> SerializableMessage {
+    let mut rebuilt = SerializableMessage::new(
+        message.get_user(),
+        message.get_message_type(),
+        message.get_content(),
+    )
+    .set_room(message.get_room())
+    // Whatever newer clients send that we don't know about yet is passed along as is.
+    .set_unknown_fields(message.get_unknown_fields());
+
+    if let Some(recipient) = message.get_recipient() {
+        rebuilt = rebuilt.set_recipient(recipient);
+    }
+    if let Some(target) = message.get_target() {
+        rebuilt = rebuilt.set_target(target);
+    }
+    if let Some(reply_to) = message.get_reply_to() {
+        rebuilt = rebuilt.set_reply_to(reply_to);
+    }
+    rebuilt
+}
+
+/// Roughly Unicode's `Extended_Pictographic`, which is what emoji are built on.
+fn is_pictographic(c: char) -> bool {
+    matches!(c,
+        '\u{A9}' | '\u{AE}' | '\u{203C}' | '\u{2049}' | '\u{2122}' | '\u{2139}'
+        | '\u{2194}'..='\u{21AA}'
+        | '\u{231A}'..='\u{23FF}'
+        | '\u{24C2}'
+        | '\u{25AA}'..='\u{27BF}'
+        | '\u{2934}'..='\u{2935}'
+        | '\u{2B05}'..='\u{2B55}'
+        | '\u{3030}' | '\u{303D}' | '\u{3297}' | '\u{3299}'
+        | '\u{1F000}'..='\u{1FAFF}'
+    )
+}
+
+/// Whether `content` is one emoji: a single grapheme that starts with a pictograph, with nothing
+/// in it but more of those and what glues them together (joiners, the emoji variation selector,
+/// and the tags subdivision flags use). Skin tones and regional indicators count as pictographs.
+fn is_emoji(content: &str) -> bool {
+    let is_glue = |c| matches!(c, '\u{200D}' | '\u{FE0F}' | '\u{E0020}'..='\u{E007F}');
+    content.chars().next().is_some_and(is_pictographic)
+        && content.chars().count() <= MAX_EMOJI_LENGTH
+        && content.chars().all(|c| is_pictographic(c) || is_glue(c))
+        && content.graphemes(true).count() == 1
+}
+
+/// Who sent a message and where, so only they can edit or delete it later.
+struct Owner {
+    uuid: Uuid,
+    room: String,
+}
+
+pub struct ServerState {
+    peers: HashMap<SocketAddr, Peer>,
+    history: History,
+    storage: Storage,
+    owners: HashMap<u64, Owner>,
+    reactions: HashMap<u64, BTreeMap<String, HashSet<Uuid>>>,
+    /// The ID each recent message got, by the session it came from and its client ID.
+    /// `handled_order` has the same keys, oldest first.
+    handled: HashMap<(Uuid, u64), Option<u64>>,
+    handled_order: VecDeque<(Uuid, u64)>,
+}
+
+impl ServerState {
+    /// Rebuilds the scrollback and who owns what from everything in `storage`.
+    pub fn open(history_length: usize, storage: Storage) -> Result<Self, StorageError> {
+        let mut state = Self {
+            peers: HashMap::new(),
+            history: History::new(history_length),
+            storage,
+            owners: HashMap::new(),
+            reactions: HashMap::new(),
+            handled: HashMap::new(),
+            handled_order: VecDeque::new(),
+        };
+
+        for message in state.storage.load()? {
+            state.remember(&message);
+        }
+
+        Ok(state)
+    }
+
+    pub fn add_peer(&mut self, addr: SocketAddr, peer: Peer) {
+        self.peers.insert(addr, peer);
+        self.replay_history(addr, DEFAULT_ROOM);
+    }
+
+    pub fn remove_peer(&mut self, addr: SocketAddr) {
+        // Say goodbye for anyone who didn't get the chance to. Rooms they left themselves are
+        // already gone from here, so nobody leaves anywhere twice.
+        if let Some(peer) = self.peers.get(&addr)
+            && let Some(user) = peer.user.clone()
+        {
+            let mut rooms: Vec<String> = peer.rooms.iter().cloned().collect();
+            rooms.sort();
+            for room in rooms {
+                self.route_message(
+                    addr,
+                    &SerializableMessage::new(
+                        user.clone(),
+                        SerializableMessageType::Leave,
+                        String::new(),
+                    )
+                    .set_room(room),
+                );
+            }
+        }
+
+        let Some(peer) = self.peers.remove(&addr) else {
+            return;
+        };
+
+        if let Some(user) = peer.user
+            && !self.is_online(&user)
+        {
+            self.broadcast_presence(SerializableMessageType::Offline, &user);
+        }
+    }
+
+    /// Whether any connection is still around for `user`.
+    fn is_online(&self, user: &User) -> bool {
+        self.peers.values().any(|peer| {
+            peer.user
+                .as_ref()
+                .is_some_and(|online| online.get_uuid() == user.get_uuid())
+        })
+    }
+
+    /// Everyone who's online, once each no matter how many connections they have open.
+    fn roster(&self) -> Vec<User> {
+        let mut seen = HashSet::new();
+        self.peers
+            .values()
+            .filter_map(|peer| peer.user.as_ref())
+            .filter(|user| seen.insert(user.get_uuid()))
+            .map(|user| user.clone().clear_uuid())
+            .collect()
+    }
+
+    /// Whether someone else already goes by `username`, ignoring case. The same person connected
+    /// twice doesn't count.
+    fn nickname_taken(&self, addr: SocketAddr, user: &User, username: &str) -> bool {
+        let username = username.to_lowercase();
+        self.peers.iter().any(|(peer_addr, peer)| {
+            *peer_addr != addr
+                && peer.user.as_ref().is_some_and(|other| {
+                    other.get_uuid() != user.get_uuid()
+                        && other.get_username().to_lowercase() == username
+                })
+        })
+    }
+
+    /// Registering and logging in go no further than the server, passwords and all. If `message`
+    /// is one of those, this returns the password check it needs, which is left to the caller so
+    /// nobody else is kept waiting while it happens.
+    fn handle_account(
+        &self,
+        addr: SocketAddr,
+        client_id: Option<u64>,
+        message: &SerializableMessage,
+    ) -> Result<Option<PasswordCheck>, Rejection> {
+        let username = message.get_user().get_username();
+
+        let password_hash = match message.get_message_type() {
+            SerializableMessageType::Register => {
+                if message.get_content().is_empty() {
+                    return Err((
+                        ErrorCode::Malformed,
+                        String::from("Registering needs a password"),
+                    ));
+                }
+                if self.account(&username)?.is_some() {
+                    return Err(already_registered(&username));
+                }
+                None
+            }
+            SerializableMessageType::Login => Some(self.account(&username)?.ok_or_else(|| {
+                (
+                    ErrorCode::NotRegistered,
+                    format!("The nickname {username} isn't registered"),
+                )
+            })?),
+            _ => return Ok(None),
+        };
+
+        Ok(Some(PasswordCheck {
+            addr,
+            client_id,
+            message: message.clone(),
+            password_hash,
+        }))
+    }
+
+    /// Picks up where `receive` left off, once a register or login's password has been checked.
+    pub fn finish_account(&mut self, checked: CheckedPassword) {
+        let CheckedPassword {
+            addr,
+            client_id,
+            message,
+            outcome,
+        } = checked;
+
+        if let Err(rejection) = self.try_finish_account(addr, &message, outcome) {
+            let message = match client_id {
+                Some(client_id) => message.set_client_id(client_id),
+                None => message,
+            };
+            self.reject(addr, &message, rejection);
+            return;
+        }
+        self.ack(addr, client_id, None);
+    }
+
+    fn try_finish_account(
+        &mut self,
+        addr: SocketAddr,
+        message: &SerializableMessage,
+        outcome: Result<Option<String>, Rejection>,
+    ) -> Result<(), Rejection> {
+        let user = message.get_user();
+        let username = user.get_username();
+
+        if let Some(password_hash) = outcome? {
+            // Someone else could have registered it while the password was being hashed.
+            if self.account(&username)?.is_some() {
+                return Err(already_registered(&username));
+            }
+            self.storage
+                .add_account(&user, password_hash)
+                .map_err(|err| {
+                    internal(&format!(
+                        "Failed to store the account for {username}: {err}"
+                    ))
+                })?;
+        }
+
+        if let Some(peer) = self.peers.get_mut(&addr) {
+            peer.account = Some(username.to_lowercase());
+            peer.send(&SerializableMessage::new(
+                User::new_static(),
+                SerializableMessageType::LoggedIn,
+                username,
+            ));
+        }
+        Ok(())
+    }
+
+    fn account(&self, username: &str) -> Result<Option<String>, Rejection> {
+        self.storage.account(username).map_err(|err| {
+            internal(&format!(
+                "Failed to look up the account for {username}: {err}"
+            ))
+        })
+    }
+
+    /// Registered nicknames can only be used by whoever logged in to them. If accounts can't be
+    /// checked, nobody gets the benefit of the doubt.
+    fn needs_login(&self, addr: SocketAddr, nickname: &str) -> bool {
+        let logged_in = self
+            .peers
+            .get(&addr)
+            .is_some_and(|peer| peer.account.as_deref() == Some(nickname.to_lowercase().as_str()));
+        !logged_in
+            && self
+                .account(nickname)
+                .map_or(true, |account| account.is_some())
+    }
+
+    /// Sends the connection at `addr` a close frame, then drops them like anyone else leaving.
+    pub fn close_peer(&mut self, addr: SocketAddr, close: CloseFrame) {
+        if let Some(peer) = self.peers.get(&addr) {
+            peer.send_frame(Message::Close(Some(close)));
+        }
+        self.remove_peer(addr);
+    }
+
+    /// Tells just the connection at `addr` that something went wrong.
+    pub fn send_error(&self, addr: SocketAddr, code: ErrorCode, error: String) {
+        if let Some(peer) = self.peers.get(&addr) {
+            peer.send(&SerializableMessage::new_error(code, error));
+        }
+    }
+
+    /// Tells just the connection at `addr` that `message` didn't go through, and why.
+    fn reject(&self, addr: SocketAddr, message: &SerializableMessage, (code, error): Rejection) {
+        println!("Turned down a message from {addr}: {error}");
+
+        let reply = SerializableMessage::new_error(code, error);
+        let reply = match message.get_client_id() {
+            Some(client_id) => reply.set_client_id(client_id),
+            None => reply,
+        };
+        if let Some(peer) = self.peers.get(&addr) {
+            peer.send(&reply);
+        }
+    }
+
+    /// Lets the connection at `addr` know their message went through (and what ID it got, if it
+    /// was stored), as long as they gave it a client ID to be told about.
+    fn ack(&self, addr: SocketAddr, client_id: Option<u64>, id: Option<u64>) {
+        let (Some(client_id), Some(peer)) = (client_id, self.peers.get(&addr)) else {
+            return;
+        };
+
+        let ack = SerializableMessage::new(
+            User::new_static(),
+            SerializableMessageType::Ack,
+            String::new(),
+        )
+        .set_client_id(client_id);
+        peer.send(&match id {
+            Some(id) => ack.set_id(id),
+            None => ack,
+        });
+    }
+
+    /// The session and client ID that pick out a message from `addr`, if it has both.
+    fn handled_key(&self, addr: SocketAddr, client_id: Option<u64>) -> Option<(Uuid, u64)> {
+        Some((self.peers.get(&addr)?.session?, client_id?))
+    }
+
+    /// Remembers that the message from `addr` with `client_id` went through, and got `id`.
+    fn mark_handled(&mut self, addr: SocketAddr, client_id: Option<u64>, id: Option<u64>) {
+        let Some(key) = self.handled_key(addr, client_id) else {
+            return;
+        };
+        if self.handled.insert(key, id).is_none() {
+            self.handled_order.push_back(key);
+        }
+        if self.handled_order.len() > REMEMBERED_CLIENT_IDS
+            && let Some(oldest) = self.handled_order.pop_front()
+        {
+            self.handled.remove(&oldest);
+        }
+    }
+
+    /// Messages get sent again after reconnecting when their ack was lost along with the
+    /// connection. If the one with `client_id` already went through once, all it needs is that
+    /// ack, and this returns `true` once it's sent.
+    fn ack_again(&self, addr: SocketAddr, client_id: Option<u64>) -> bool {
+        let Some(&id) = self
+            .handled_key(addr, client_id)
+            .and_then(|key| self.handled.get(&key))
+        else {
+            return false;
+        };
+        self.ack(addr, client_id, id);
+        true
+    }
+
+    /// Remembers what the client at `addr` can do, and tells them what we can.
+    fn welcome(&mut self, addr: SocketAddr, hello: &SerializableMessage) {
+        let Some(peer) = self.peers.get_mut(&addr) else {
+            return;
+        };
+
+        peer.capabilities = Capabilities::from_message(hello);
+        peer.said_hello = true;
+        peer.session = hello.get_session();
+        println!(
+            "{addr} speaks protocol version {}",
+            peer.capabilities.get_version()
+        );
+        peer.send(&Capabilities::ours().to_message(SerializableMessageType::Welcome));
+    }
+
+    fn send_roster(&self, addr: SocketAddr) {
+        if let Some(peer) = self.peers.get(&addr) {
+            peer.send(
+                &SerializableMessage::new(
+                    User::new_static(),
+                    SerializableMessageType::Roster,
+                    String::new(),
+                )
+                .set_roster(self.roster()),
+            );
+        }
+    }
+
+    fn broadcast_presence(&self, message_type: SerializableMessageType, user: &User) {
+        let message =
+            SerializableMessage::new(user.clone().clear_uuid(), message_type, String::new());
+        self.peers.values().for_each(|peer| peer.send(&message));
+    }
+
+    /// Ties `user` to the connection at `addr`, and lets everyone know if they just showed up or
+    /// now look different.
+    fn bind_user(&mut self, addr: SocketAddr, user: &User) {
+        let Some(peer) = self.peers.get_mut(&addr) else {
+            return;
+        };
+
+        let previous = peer.user.take();
+        let was_online = self.is_online(user);
+        if let Some(peer) = self.peers.get_mut(&addr) {
+            peer.user = Some(user.clone());
+        }
+
+        match previous {
+            Some(previous)
+                if previous.get_username() == user.get_username()
+                    && previous.get_color() == user.get_color() => {}
+            Some(previous) => {
+                self.broadcast_presence(SerializableMessageType::Offline, &previous);
+                self.broadcast_presence(SerializableMessageType::Online, user);
+            }
+            None if !was_online => {
+                self.broadcast_presence(SerializableMessageType::Online, user);
+            }
+            None => {}
+        }
+    }
+
+    fn replay_history(&self, addr: SocketAddr, room: &str) {
+        let Some(peer) = self.peers.get(&addr) else {
+            return;
+        };
+
+        for message in self.history.replay(room) {
+            peer.send(message);
+        }
+    }
+
+    /// Keeps track of a stored message, which still has its sender's real UUID.
+    fn remember(&mut self, message: &SerializableMessage) {
+        match (
+            message.get_message_type(),
+            message.get_id(),
+            message.get_target(),
+        ) {
+            (SerializableMessageType::Text, Some(id), _) => {
+                self.owners.insert(
+                    id,
+                    Owner {
+                        uuid: message.get_user().get_uuid(),
+                        room: message.get_room(),
+                    },
+                );
+            }
+            (SerializableMessageType::Delete, _, Some(target)) => {
+                self.owners.remove(&target);
+                self.reactions.remove(&target);
+            }
+            // Reacting with the same emoji twice takes it back.
+            (SerializableMessageType::Reaction, _, Some(target)) => {
+                let reactors = self
+                    .reactions
+                    .entry(target)
+                    .or_default()
+                    .entry(message.get_content())
+                    .or_default();
+                let uuid = message.get_user().get_uuid();
+                if !reactors.remove(&uuid) {
+                    reactors.insert(uuid);
+                }
+
+                let tally = self.tally(target);
+                self.history
+                    .set_reactions(&message.get_room(), target, tally);
+            }
+            _ => {}
+        }
+
+        self.history
+            .record(&message.clone().set_user(message.get_user().clear_uuid()));
+    }
+
+    /// How many people reacted to `target` with each emoji.
+    fn tally(&self, target: u64) -> BTreeMap<String, u32> {
+        self.reactions
+            .get(&target)
+            .into_iter()
+            .flatten()
+            .filter(|(_, reactors)| !reactors.is_empty())
+            .map(|(emoji, reactors)| {
+                (
+                    emoji.clone(),
+                    u32::try_from(reactors.len()).unwrap_or(u32::MAX),
+                )
+            })
+            .collect()
+    }
+
+    /// Edits and deletes are only allowed on your own messages, and always go to the room the
+    /// original was in. Returns that room, or `None` if this isn't allowed.
+    fn owned_room(&self, message: &SerializableMessage) -> Option<String> {
+        let owner = self.owners.get(&message.get_target()?)?;
+        (owner.uuid == message.get_user().get_uuid()).then(|| owner.room.clone())
+    }
+
+    fn recipients(
+        &self,
+        addr: SocketAddr,
+        message: &SerializableMessage,
+    ) -> Result<Vec<SocketAddr>, Rejection> {
+        let Some(sender) = self.peers.get(&addr) else {
+            return Ok(Vec::new());
+        };
+        let room = message.get_room();
+
+        // Renames aren't tied to a single room, so everyone who can see the sender gets them.
+        // Direct messages go to the recipient, plus the sender so they can see what they sent.
+        // Everything else only goes to the people in the room it was sent to.
+        match message.get_message_type() {
+            SerializableMessageType::Rename => Ok(self
+                .peers
+                .iter()
+                .filter(|(_, peer)| peer.shares_room_with(sender))
+                .map(|(peer_addr, _)| *peer_addr)
+                .collect()),
+            SerializableMessageType::Direct => {
+                let Some(recipient) = message.get_recipient() else {
+                    return Err((
+                        ErrorCode::Malformed,
+                        String::from("Direct messages need someone to go to"),
+                    ));
+                };
+
+                // Nicknames are only unique ignoring case, so that's how they're matched too.
+                let lowercase = recipient.to_lowercase();
+                let is_recipient = |user: &User| user.get_username().to_lowercase() == lowercase;
+                let recipients: Vec<SocketAddr> = self
+                    .peers
+                    .iter()
+                    .filter(|(peer_addr, peer)| {
+                        **peer_addr != addr && peer.user.as_ref().is_some_and(is_recipient)
+                    })
+                    .map(|(peer_addr, _)| *peer_addr)
+                    .collect();
+
+                if recipients.is_empty() {
+                    return Err((ErrorCode::NoSuchUser, format!("{recipient} isn't here")));
+                }
+                // They'd never see it, so better the sender hears about it than thinks it arrived.
+                if recipients.iter().any(|peer_addr| {
+                    self.peers
+                        .get(peer_addr)
+                        .is_some_and(|peer| !peer.capabilities.supports(Capability::Direct))
+                }) {
+                    return Err((
+                        ErrorCode::Unsupported,
+                        format!("{recipient} can't get direct messages"),
+                    ));
+                }
+
+                Ok(recipients
+                    .into_iter()
+                    .chain(std::iter::once(addr))
+                    .collect())
+            }
+            SerializableMessageType::Join
+            | SerializableMessageType::Leave
+            | SerializableMessageType::Text
+            | SerializableMessageType::Edit
+            | SerializableMessageType::Delete
+            | SerializableMessageType::Reaction
+            | SerializableMessageType::Typing => {
+                if !sender.rooms.contains(&room) {
+                    return Err((ErrorCode::NotInRoom, format!("You aren't in #{room}")));
+                }
+
+                // There's no point telling someone that they themselves are typing.
+                let is_typing =
+                    matches!(message.get_message_type(), SerializableMessageType::Typing);
+                Ok(self
+                    .peers
+                    .iter()
+                    .filter(|(peer_addr, peer)| {
+                        peer.rooms.contains(&room) && !(is_typing && **peer_addr == addr)
+                    })
+                    .map(|(peer_addr, _)| *peer_addr)
+                    .collect())
+            }
+            // These are either answered by the server itself, or only ever come from it.
+            SerializableMessageType::Who
+            | SerializableMessageType::Roster
+            | SerializableMessageType::Online
+            | SerializableMessageType::Offline
+            | SerializableMessageType::Error
+            | SerializableMessageType::Register
+            | SerializableMessageType::Login
+            | SerializableMessageType::LoggedIn
+            | SerializableMessageType::Ack
+            | SerializableMessageType::Hello
+            | SerializableMessageType::Welcome => Err((
+                ErrorCode::ServerOnly,
+                String::from("Only the server gets to send that"),
+            )),
+        }
+    }
+
+    /// Swaps whoever the client says sent `message` for who the connection really is. Joins (and
+    /// logins) are where the name and color come from, renames can only change the color, and
+    /// everything else gets the bound identity as is. Nothing but those gets through before joining,
+    /// unless the client is too old to know about joining, in which case its first message will do.
+    fn identify(
+        &self,
+        addr: SocketAddr,
+        message: SerializableMessage,
+    ) -> Result<SerializableMessage, Rejection> {
+        let bound = self.peers.get(&addr).and_then(|peer| peer.user.clone());
+        let old_client = !self.said_hello(addr);
+        let claimed = message.get_user();
+
+        let user = match (bound, message.get_message_type()) {
+            (
+                Some(bound),
+                SerializableMessageType::Join
+                | SerializableMessageType::Register
+                | SerializableMessageType::Login,
+            ) => bound
+                .set_username(claimed.get_username())
+                .set_color(claimed.get_color()),
+            (Some(bound), SerializableMessageType::Rename) => bound.set_color(claimed.get_color()),
+            (Some(bound), _) => bound,
+            (
+                None,
+                SerializableMessageType::Join
+                | SerializableMessageType::Register
+                | SerializableMessageType::Login
+                | SerializableMessageType::Who,
+            ) => claimed,
+            (None, _) if old_client => claimed,
+            (None, _) => {
+                return Err((
+                    ErrorCode::NotJoined,
+                    String::from("Join a room before saying anything"),
+                ));
+            }
+        };
+
+        Ok(message.set_user(user))
+    }
+
+    fn said_hello(&self, addr: SocketAddr) -> bool {
+        self.peers.get(&addr).is_some_and(|peer| peer.said_hello)
+    }
+
+    /// Makes sure the nickname `message` is using isn't someone else's.
+    fn check_nickname(
+        &self,
+        addr: SocketAddr,
+        message: &SerializableMessage,
+    ) -> Result<(), Rejection> {
+        // Renames claim a new name, everything else carries on with the one they've got. Checking
+        // every message and not just joins means nobody can skip straight to talking as someone else.
+        let nickname = if matches!(message.get_message_type(), SerializableMessageType::Rename) {
+            message.get_content()
+        } else {
+            message.get_user().get_username()
+        };
+
+        if self.nickname_taken(addr, &message.get_user(), &nickname) {
+            return Err((
+                ErrorCode::NicknameTaken,
+                format!("The nickname {nickname} is already taken"),
+            ));
+        }
+        if self.needs_login(addr, &nickname)
+            && !matches!(
+                message.get_message_type(),
+                SerializableMessageType::Register | SerializableMessageType::Login
+            )
+        {
+            return Err((
+                ErrorCode::NicknameRegistered,
+                format!("The nickname {nickname} is registered, log in to use it"),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Edits, deletes and reactions always go to the room of the message they're about, whatever
+    /// the client said. Returns `None` if they aren't allowed.
+    fn resolve_room(&self, message: SerializableMessage) -> Result<SerializableMessage, Rejection> {
+        match message.get_message_type() {
+            SerializableMessageType::Edit | SerializableMessageType::Delete => {
+                let Some(room) = self.owned_room(&message) else {
+                    return Err((
+                        ErrorCode::NotYours,
+                        String::from("You can only change your own messages"),
+                    ));
+                };
+                Ok(message.set_room(room))
+            }
+            SerializableMessageType::Reaction => {
+                let content = message.get_content();
+                let Some(owner) = message
+                    .get_target()
+                    .and_then(|target| self.owners.get(&target))
+                    .filter(|_| is_emoji(&content))
+                else {
+                    return Err((
+                        ErrorCode::InvalidReaction,
+                        String::from(
+                            "Reactions need to be a single emoji on a message that exists",
+                        ),
+                    ));
+                };
+                Ok(message.set_room(owner.room.clone()))
+            }
+            _ => Ok(message),
+        }
+    }
+
+    /// Everything a client sends comes through here, so it's where rate limiting happens.
+    /// Registering and logging in come back out as a `PasswordCheck`, which needs running and
+    /// handing to `finish_account`.
+    pub fn receive(
+        &mut self,
+        addr: SocketAddr,
+        message: &SerializableMessage,
+    ) -> Option<PasswordCheck> {
+        let allowed = self
+            .peers
+            .get_mut(&addr)
+            .is_some_and(|peer| peer.rate_limiter.allow());
+        if !allowed {
+            self.reject(
+                addr,
+                message,
+                (
+                    ErrorCode::RateLimited,
+                    String::from("Slow down, you're sending too much"),
+                ),
+            );
+            return None;
+        }
+
+        match self.try_route_message(addr, message) {
+            Ok(check) => check,
+            Err(rejection) => {
+                self.reject(addr, message, rejection);
+                None
+            }
+        }
+    }
+
+    /// For messages the server sends on someone's behalf, which are never about accounts.
+    fn route_message(&mut self, addr: SocketAddr, message: &SerializableMessage) {
+        if let Err(rejection) = self.try_route_message(addr, message) {
+            self.reject(addr, message, rejection);
+        }
+    }
+
+    fn try_route_message(
+        &mut self,
+        addr: SocketAddr,
+        message: &SerializableMessage,
+    ) -> Result<Option<PasswordCheck>, Rejection> {
+        let message_type = message.get_message_type();
+        let client_id = message.get_client_id();
+
+        if matches!(message_type, SerializableMessageType::Hello) {
+            self.welcome(addr, message);
+            return Ok(None);
+        }
+
+        let message = self.identify(addr, from_client(message))?;
+
+        if self.ack_again(addr, client_id) {
+            return Ok(None);
+        }
+
+        let message = self.resolve_room(message)?;
+
+        if matches!(message_type, SerializableMessageType::Who) {
+            self.send_roster(addr);
+            self.ack(addr, client_id, None);
+            return Ok(None);
+        }
+
+        let room = message.get_room();
+        self.check_nickname(addr, &message)?;
+
+        if let Some(check) = self.handle_account(addr, client_id, &message)? {
+            return Ok(Some(check));
+        }
+
+        // Joining is when a connection says who it is, as far as everything else goes. Clients that
+        // never said hello might not know how to join, so they are whoever they first say they are.
+        let unbound = self
+            .peers
+            .get(&addr)
+            .is_some_and(|peer| peer.user.is_none());
+        if matches!(message_type, SerializableMessageType::Join)
+            || (unbound && !self.said_hello(addr))
+        {
+            self.bind_user(addr, &message.get_user());
+        }
+
+        if matches!(message_type, SerializableMessageType::Join)
+            && self
+                .peers
+                .get_mut(&addr)
+                .is_some_and(|sender| sender.rooms.insert(room.clone()))
+        {
+            self.replay_history(addr, &room);
+        }
+
+        let broadcast_recipients = self.recipients(addr, &message)?;
+
+        // Typing indicators are fire and forget, so they're the one thing that isn't kept.
+        // What does get stored keeps the sender's UUID, but nobody else gets to see it.
+        let message = if matches!(message_type, SerializableMessageType::Typing) {
+            message
+        } else {
+            let stamped = match self.storage.record(&message) {
+                Ok(stamped) => stamped,
+                Err(err) => {
+                    println!("Failed to store message from {addr}: {err}");
+                    message
+                }
+            };
+            self.remember(&stamped);
+            stamped
+        };
+
+        let mut new_message = message.clone().set_user(message.get_user().clear_uuid());
+        if let (SerializableMessageType::Reaction, Some(target)) =
+            (&message_type, message.get_target())
+        {
+            new_message = new_message.set_reactions(self.tally(target));
+        }
+
+        for recp in broadcast_recipients
+            .iter()
+            .filter_map(|recp_addr| self.peers.get(recp_addr))
+        {
+            recp.send(&new_message);
+        }
+
+        match message_type {
+            // Leaving happens after the broadcast so the leaver still sees their own goodbye.
+            SerializableMessageType::Leave => {
+                if let Some(sender) = self.peers.get_mut(&addr) {
+                    sender.rooms.remove(&room);
+                }
+            }
+            SerializableMessageType::Rename => {
+                self.bind_user(
+                    addr,
+                    &message.get_user().set_username(new_message.get_content()),
+                );
+            }
+            SerializableMessageType::Join
+            | SerializableMessageType::Text
+            | SerializableMessageType::Direct
+            | SerializableMessageType::Edit
+            | SerializableMessageType::Delete
+            | SerializableMessageType::Reaction
+            | SerializableMessageType::Typing
+            | SerializableMessageType::Who
+            | SerializableMessageType::Roster
+            | SerializableMessageType::Online
+            | SerializableMessageType::Offline
+            | SerializableMessageType::Error
+            | SerializableMessageType::Register
+            | SerializableMessageType::Login
+            | SerializableMessageType::LoggedIn
+            | SerializableMessageType::Ack
+            | SerializableMessageType::Hello
+            | SerializableMessageType::Welcome => {}
+        }
+
+        self.mark_handled(addr, client_id, message.get_id());
+        self.ack(addr, client_id, message.get_id());
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::channel::mpsc::{UnboundedReceiver, unbounded};
+
+    use super::*;
+    use crate::config::StorageConfig;
+
+    fn state() -> ServerState {
+        let storage = Storage::open(&StorageConfig::Memory).expect("Failed to open storage");
+        ServerState::open(100, storage).expect("Failed to open state")
+    }
+
+    /// A new connection from `port` that's said hello as `session`, and what it gets sent.
+    fn connect(
+        state: &mut ServerState,
+        port: u16,
+        session: Uuid,
+    ) -> (SocketAddr, UnboundedReceiver<Message>) {
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let (tx, rx) = unbounded();
+        state.add_peer(addr, Peer::new(tx, Codec::Json));
+        let hello = Capabilities::ours()
+            .to_message(SerializableMessageType::Hello)
+            .set_session(session);
+        assert!(state.receive(addr, &hello).is_none());
+        (addr, rx)
+    }
+
+    /// Everything sent to a connection since last time, apart from the `Welcome`.
+    fn received(rx: &mut UnboundedReceiver<Message>) -> Vec<SerializableMessage> {
+        std::iter::from_fn(|| rx.try_next().ok().flatten())
+            .map(|frame| {
+                Codec::Json
+                    .decode(&frame.into_data())
+                    .expect("Failed to decode")
+            })
+            .filter(|message| {
+                !matches!(message.get_message_type(), SerializableMessageType::Welcome)
+            })
+            .collect()
+    }
+
+    fn types(messages: &[SerializableMessage]) -> Vec<String> {
+        messages
+            .iter()
+            .map(|message| format!("{:?}", message.get_message_type()))
+            .collect()
+    }
+
+    #[test]
+    fn messages_sent_again_are_only_acked_again() {
+        let mut state = state();
+        let user = User::new(String::from("alice"));
+        let session = Uuid::new_v4();
+        let join =
+            SerializableMessage::new(user.clone(), SerializableMessageType::Join, String::new())
+                .set_client_id(1);
+        let text =
+            SerializableMessage::new(user, SerializableMessageType::Text, String::from("hi"))
+                .set_client_id(2);
+
+        let (addr, mut rx) = connect(&mut state, 1, session);
+        state.receive(addr, &join);
+        state.receive(addr, &text);
+        let first = received(&mut rx);
+        state.remove_peer(addr);
+
+        // The connection dropped before the ack for the text got through, so it comes round again.
+        let (addr, mut rx) = connect(&mut state, 2, session);
+        state.receive(addr, &join.clone().set_client_id(3));
+        let _rejoined = received(&mut rx);
+        state.receive(addr, &text);
+
+        let again = received(&mut rx);
+        assert_eq!(types(&again), ["Ack"]);
+        assert_eq!(
+            again.first().and_then(SerializableMessage::get_id),
+            first.last().and_then(SerializableMessage::get_id)
+        );
+    }
+
+    #[test]
+    fn client_ids_start_over_with_a_new_session() {
+        let mut state = state();
+        let user = User::new(String::from("alice"));
+        let join =
+            SerializableMessage::new(user.clone(), SerializableMessageType::Join, String::new())
+                .set_client_id(1);
+        let text =
+            SerializableMessage::new(user, SerializableMessageType::Text, String::from("hi"))
+                .set_client_id(2);
+
+        let (addr, _rx) = connect(&mut state, 1, Uuid::new_v4());
+        state.receive(addr, &join);
+        state.receive(addr, &text);
+        state.remove_peer(addr);
+
+        // Restarted with the same user, so the same UUID, and counting from 1 again.
+        let (addr, mut rx) = connect(&mut state, 2, Uuid::new_v4());
+        let _history = received(&mut rx);
+        state.receive(addr, &join);
+        state.receive(addr, &text);
+
+        assert_eq!(
+            types(&received(&mut rx)),
+            ["Online", "Join", "Ack", "Text", "Ack"]
+        );
+    }
+
+    #[test]
+    fn direct_messages_to_clients_that_cant_get_them_are_turned_down() {
+        let mut state = state();
+        let (addr, mut rx) = connect(&mut state, 1, Uuid::new_v4());
+        let alice = User::new(String::from("alice"));
+        state.receive(
+            addr,
+            &SerializableMessage::new(alice.clone(), SerializableMessageType::Join, String::new()),
+        );
+
+        // Never said hello, so it can't do anything newer than text.
+        let (tx, _old_rx) = unbounded();
+        let old = SocketAddr::from(([127, 0, 0, 1], 2));
+        state.add_peer(old, Peer::new(tx, Codec::Json));
+        state.receive(
+            old,
+            &SerializableMessage::new(
+                User::new(String::from("bob")),
+                SerializableMessageType::Text,
+                String::from("hi"),
+            ),
+        );
+        let _so_far = received(&mut rx);
+
+        state.receive(
+            addr,
+            &SerializableMessage::new_direct(alice, String::from("bob"), String::from("psst"))
+                .set_client_id(7),
+        );
+
+        let answer = received(&mut rx);
+        assert_eq!(types(&answer), ["Error"]);
+        assert_eq!(
+            answer.first().and_then(SerializableMessage::get_error),
+            Some(ErrorCode::Unsupported)
+        );
+        assert_eq!(
+            answer.first().and_then(SerializableMessage::get_client_id),
+            Some(7)
+        );
+    }
+
+    #[test]
+    fn only_single_emoji_are_reactions() {
+        for emoji in ["👍", "❤️", "👍🏽", "🇳🇱", "👩‍👩‍👧‍👦", "👩🏻‍❤️‍💋‍👨🏼", "🏴󠁧󠁢󠁳󠁣󠁴󠁿"]
+        {
+            assert!(is_emoji(emoji), "{emoji}");
+        }
+        for not_emoji in [
+            "", "lol", "<script>", "👍 ", " 👍", "👍👍", "a👍", "\u{FE0F}", "1️⃣",
+        ] {
+            assert!(!is_emoji(not_emoji), "{not_emoji:?}");
+        }
+    }
+}
@@ -0,0 +1,311 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, BufRead as _, BufReader, Read as _, Seek as _, SeekFrom, Write as _},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use rusqlite::{Connection, OptionalExtension as _};
+use sillirc_lib::networker::{SerializableMessage, SerializableMessageType};
+use sillirc_lib::user::User;
+
+use crate::config::StorageConfig;
+
+#[derive(Debug)]
+pub enum StorageError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    Sqlite(rusqlite::Error),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "storage io error: {err}"),
+            Self::Json(err) => write!(f, "storage json error: {err}"),
+            Self::Sqlite(err) => write!(f, "storage sqlite error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<io::Error> for StorageError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for StorageError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Json(err)
+    }
+}
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(err: rusqlite::Error) -> Self {
+        Self::Sqlite(err)
+    }
+}
+
+/// Accounts are looked up by lowercased nickname, so they're as case-insensitive as nicknames are.
+type Accounts = HashMap<String, String>;
+
+pub enum Storage {
+    Memory {
+        next_id: u64,
+        accounts: Accounts,
+    },
+    Log {
+        file: File,
+        next_id: u64,
+        accounts: Accounts,
+    },
+    Sqlite {
+        connection: Connection,
+    },
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| u64::try_from(since_epoch.as_millis()).unwrap_or(u64::MAX))
+        .unwrap_or_default()
+}
+
+impl Storage {
+    pub fn open(config: &StorageConfig) -> Result<Self, StorageError> {
+        match config {
+            StorageConfig::Memory => Ok(Self::Memory {
+                next_id: 1,
+                accounts: Accounts::new(),
+            }),
+            StorageConfig::Log(path) => Self::open_log(path),
+            StorageConfig::Sqlite(path) => Self::open_sqlite(path),
+        }
+    }
+
+    fn open_log(path: &Path) -> Result<Self, StorageError> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+        Self::end_log(&mut file)?;
+
+        let lines = Self::read_log(&file)?;
+        let next_id = lines
+            .iter()
+            .filter_map(SerializableMessage::get_id)
+            .max()
+            .map_or(1, |id| id + 1);
+
+        // Accounts live in the log too, as `Register`s with the password hash in place of the
+        // password.
+        let accounts = lines
+            .iter()
+            .filter(|line| is_account(line))
+            .map(|line| {
+                (
+                    line.get_user().get_username().to_lowercase(),
+                    line.get_content(),
+                )
+            })
+            .collect();
+
+        Ok(Self::Log {
+            file,
+            next_id,
+            accounts,
+        })
+    }
+
+    fn read_log(file: &File) -> Result<Vec<SerializableMessage>, StorageError> {
+        // Appends always go to the end no matter where the cursor is, so it's fine to move it.
+        let mut reader = file.try_clone()?;
+        reader.seek(SeekFrom::Start(0))?;
+
+        // A crash halfway through an append leaves half a line behind, and losing that one
+        // message beats losing everything else.
+        let mut messages = Vec::new();
+        for (number, line) in BufReader::new(reader).lines().enumerate() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            match serde_json::from_str(&line) {
+                Ok(message) => messages.push(message),
+                Err(err) => println!("Skipping line {} of the log: {err}", number + 1),
+            }
+        }
+        Ok(messages)
+    }
+
+    /// Finishes off a half-written last line, so the next append doesn't get stuck to the end of it.
+    fn end_log(file: &mut File) -> io::Result<()> {
+        let mut reader = file.try_clone()?;
+        if reader.seek(SeekFrom::End(0))? == 0 {
+            return Ok(());
+        }
+        reader.seek(SeekFrom::End(-1))?;
+
+        let mut last = [0];
+        reader.read_exact(&mut last)?;
+        if last != *b"\n" {
+            writeln!(file)?;
+        }
+        Ok(())
+    }
+
+    fn open_sqlite(path: &Path) -> Result<Self, StorageError> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS messages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp INTEGER NOT NULL,
+                room TEXT NOT NULL,
+                username TEXT NOT NULL,
+                message_type TEXT NOT NULL,
+                content TEXT NOT NULL,
+                message TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS accounts (
+                username TEXT PRIMARY KEY,
+                password_hash TEXT NOT NULL
+            );",
+        )?;
+
+        Ok(Self::Sqlite { connection })
+    }
+
+    /// Stamps `message` with an ID and the current time, and writes it down.
+    pub fn record(
+        &mut self,
+        message: &SerializableMessage,
+    ) -> Result<SerializableMessage, StorageError> {
+        let timestamp = now();
+
+        match self {
+            Self::Memory { next_id, .. } => {
+                let stamped = message.clone().set_id(*next_id).set_timestamp(timestamp);
+                *next_id += 1;
+                Ok(stamped)
+            }
+            Self::Log { file, next_id, .. } => {
+                let stamped = message.clone().set_id(*next_id).set_timestamp(timestamp);
+                writeln!(file, "{}", serde_json::to_string(&stamped)?)?;
+                file.flush()?;
+                *next_id += 1;
+                Ok(stamped)
+            }
+            Self::Sqlite { connection } => {
+                connection.execute(
+                    "INSERT INTO messages (timestamp, room, username, message_type, content, message)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    (
+                        i64::try_from(timestamp).unwrap_or(i64::MAX),
+                        message.get_room(),
+                        message.get_user().get_username(),
+                        format!("{:?}", message.get_message_type()),
+                        message.get_content(),
+                        serde_json::to_string(message)?,
+                    ),
+                )?;
+
+                let id = u64::try_from(connection.last_insert_rowid()).unwrap_or_default();
+                Ok(message.clone().set_id(id).set_timestamp(timestamp))
+            }
+        }
+    }
+
+    /// Everything that has been recorded so far, oldest first.
+    pub fn load(&self) -> Result<Vec<SerializableMessage>, StorageError> {
+        match self {
+            Self::Memory { .. } => Ok(Vec::new()),
+            Self::Log { file, .. } => Ok(Self::read_log(file)?
+                .into_iter()
+                .filter(|line| !is_account(line))
+                .collect()),
+            Self::Sqlite { connection } => {
+                let mut statement = connection
+                    .prepare("SELECT id, timestamp, message FROM messages ORDER BY id")?;
+                let rows = statement.query_map((), |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, i64>(1)?,
+                        row.get::<_, String>(2)?,
+                    ))
+                })?;
+
+                // Same as with the log, one bad row shouldn't cost everything else.
+                let mut messages = Vec::new();
+                for (number, row) in rows.enumerate() {
+                    let message =
+                        row.map_err(StorageError::from)
+                            .and_then(|(id, timestamp, message)| {
+                                Ok(serde_json::from_str::<SerializableMessage>(&message)?
+                                    .set_id(u64::try_from(id).unwrap_or_default())
+                                    .set_timestamp(u64::try_from(timestamp).unwrap_or_default()))
+                            });
+                    match message {
+                        Ok(message) => messages.push(message),
+                        Err(err) => {
+                            println!("Skipping row {} of the messages table: {err}", number + 1);
+                        }
+                    }
+                }
+                Ok(messages)
+            }
+        }
+    }
+
+    /// The password hash for the account registered to `username`, if there is one.
+    pub fn account(&self, username: &str) -> Result<Option<String>, StorageError> {
+        let username = username.to_lowercase();
+        match self {
+            Self::Memory { accounts, .. } | Self::Log { accounts, .. } => {
+                Ok(accounts.get(&username).cloned())
+            }
+            Self::Sqlite { connection } => Ok(connection
+                .query_row(
+                    "SELECT password_hash FROM accounts WHERE username = ?1",
+                    (username,),
+                    |row| row.get(0),
+                )
+                .optional()?),
+        }
+    }
+
+    /// Registers `username` to whoever knows the password behind `password_hash`.
+    pub fn add_account(&mut self, user: &User, password_hash: String) -> Result<(), StorageError> {
+        let username = user.get_username().to_lowercase();
+        match self {
+            Self::Memory { accounts, .. } => {
+                accounts.insert(username, password_hash);
+            }
+            Self::Log { file, accounts, .. } => {
+                let line = SerializableMessage::new(
+                    user.clone(),
+                    SerializableMessageType::Register,
+                    password_hash.clone(),
+                );
+                writeln!(file, "{}", serde_json::to_string(&line)?)?;
+                file.flush()?;
+                accounts.insert(username, password_hash);
+            }
+            Self::Sqlite { connection } => {
+                connection.execute(
+                    "INSERT INTO accounts (username, password_hash) VALUES (?1, ?2)",
+                    (username, password_hash),
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn is_account(line: &SerializableMessage) -> bool {
+    matches!(line.get_message_type(), SerializableMessageType::Register)
+}
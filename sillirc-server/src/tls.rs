@@ -0,0 +1,31 @@
+use std::sync::Arc;
+
+use sillirc_lib::tls::read_certificates;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::PrivateKeyDer;
+use tokio_rustls::rustls::pki_types::pem::PemObject as _;
+
+use crate::config::TlsConfig;
+
+/// Loads the certificate and key, ready to wrap each incoming connection in TLS.
+pub fn acceptor(config: &TlsConfig) -> Result<TlsAcceptor, String> {
+    let cert = config.cert.display();
+    let key = config.key.display();
+
+    let chain = read_certificates(&config.cert)
+        .map_err(|err| format!("couldn't read certificates from {cert}: {err}"))?;
+
+    let private_key = PrivateKeyDer::from_pem_file(&config.key)
+        .map_err(|err| format!("couldn't read a private key from {key}: {err}"))?;
+
+    let server_config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|err| err.to_string())?
+        .with_no_client_auth()
+        .with_single_cert(chain, private_key)
+        .map_err(|err| format!("{cert} and {key} don't work together: {err}"))?;
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
@@ -3,12 +3,117 @@
 use eframe::{App, egui};
 
 use eframe::glow::Context;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::Mutex;
 
-use sillirc_lib::networker::{Networker, SerializableMessage, SerializableMessageType};
+use sillirc_lib::networker::{
+    ConnectOptions, ConnectionEvent, DEFAULT_ROOM, ErrorCode, Networker, SerializableMessage,
+    SerializableMessageType,
+};
+use sillirc_lib::outbox::Outbox;
+use sillirc_lib::tls::TlsOptions;
 use sillirc_lib::user::User;
 
+/// Something done to a message from its right click menu.
+enum MessageAction {
+    Edit(u64, String),
+    Delete(u64),
+    React(u64, String),
+    Reply(u64),
+    ShowOriginal(u64),
+}
+
+/// What eframe knows us as, which decides where our files go.
+pub const APP_NAME: &str = "sillirc";
+
+/// Where we connect unless `SILLIRC_SERVER` says otherwise.
+const DEFAULT_SERVER: &str = "ws://sillirc.owomay.hackclub.app";
+
+const QUICK_REACTIONS: [&str; 6] = ["👍", "❤", "😂", "😮", "😢", "🎉"];
+
+/// How often we tell everyone we're still typing.
+const TYPING_THROTTLE: Duration = Duration::from_secs(2);
+/// How long someone counts as typing after we last heard from them.
+const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Who's typing in which room, and when we last heard about it.
+type TypingMap = HashMap<(String, String), Instant>;
+
+/// Our own messages that didn't make it, and why, by client ID.
+type FailedMap = BTreeMap<u64, (SerializableMessage, String)>;
+
+/// Sends `messages` in order, stopping at the first one that can't be sent and saying why.
+async fn send_all(
+    nw: &mut Networker,
+    messages: Vec<SerializableMessage>,
+    notice: &Mutex<Option<String>>,
+) {
+    for message in messages {
+        if let Err(err) = nw.send(message).await {
+            notice.lock().await.get_or_insert_with(|| err.to_string());
+            return;
+        }
+    }
+}
+
+/// Adds whatever the server sent to what's on screen. Edits and deletes change the message they
+/// refer to instead of showing up themselves.
+fn receive_message(messages: &mut Vec<SerializableMessage>, message: SerializableMessage) {
+    match (message.get_message_type(), message.get_target()) {
+        (SerializableMessageType::Edit, Some(target)) => {
+            if let Some(original) = messages
+                .iter_mut()
+                .find(|original| original.get_id() == Some(target))
+            {
+                *original = original
+                    .clone()
+                    .set_content(message.get_content())
+                    .set_edited(true);
+            }
+        }
+        (SerializableMessageType::Delete, Some(target)) => {
+            messages.retain(|original| original.get_id() != Some(target));
+        }
+        (SerializableMessageType::Reaction, Some(target)) => {
+            if let Some(original) = messages
+                .iter_mut()
+                .find(|original| original.get_id() == Some(target))
+            {
+                *original = original.clone().set_reactions(message.get_reactions());
+            }
+        }
+        _ => {
+            // Scrollback can be replayed more than once, so don't show things twice.
+            if message
+                .get_id()
+                .is_none_or(|id| messages.iter().all(|seen| seen.get_id() != Some(id)))
+            {
+                messages.push(message);
+            }
+        }
+    }
+}
+
+/// Keeps the member list in line with what the server says about who's online.
+fn update_members(members: &mut Vec<User>, message: &SerializableMessage) {
+    let username = message.get_user().get_username();
+    match message.get_message_type() {
+        SerializableMessageType::Roster => *members = message.get_roster(),
+        SerializableMessageType::Online => {
+            members.retain(|member| member.get_username() != username);
+            members.push(message.get_user());
+        }
+        SerializableMessageType::Offline => {
+            members.retain(|member| member.get_username() != username);
+        }
+        _ => {}
+    }
+}
+
 #[derive(serde::Deserialize, serde::Serialize)]
 #[serde(default)]
 pub struct SillircApp {
@@ -16,21 +121,50 @@ pub struct SillircApp {
     runtime: tokio::runtime::Runtime,
     #[serde(skip)]
     networker: Arc<Mutex<Option<Networker>>>,
+    /// Whether the networker's been started, which says nothing about it actually being connected.
     #[serde(skip)]
-    is_connected: bool,
+    connection_started: bool,
     #[serde(skip)]
     messages: Arc<Mutex<Vec<SerializableMessage>>>,
     #[serde(skip)]
+    failed: Arc<Mutex<FailedMap>>,
+    #[serde(skip)]
     current_text: String,
     #[serde(skip)]
     temp_username: String,
     #[serde(skip)]
+    temp_password: String,
+    #[serde(skip)]
     temp_color: [u8; 3],
     #[serde(skip)]
     renaming: bool,
     #[serde(skip)]
     coloring: bool,
+    #[serde(skip)]
+    temp_room: String,
+    #[serde(skip)]
+    editing: Option<u64>,
+    #[serde(skip)]
+    replying_to: Option<u64>,
+    #[serde(skip)]
+    scroll_to: Option<u64>,
+    #[serde(skip)]
+    typing: Arc<Mutex<TypingMap>>,
+    #[serde(skip)]
+    last_typing_sent: Option<Instant>,
+    #[serde(skip)]
+    members: Arc<Mutex<Vec<User>>>,
+    #[serde(skip)]
+    incoming_error: Arc<Mutex<Option<String>>>,
+    #[serde(skip)]
+    notice: Arc<Mutex<Option<String>>>,
+    #[serde(skip)]
+    name_error: Option<String>,
+    #[serde(skip)]
+    previous_username: Option<String>,
     user: User,
+    rooms: Vec<String>,
+    current_room: String,
 }
 
 impl SillircApp {
@@ -43,80 +177,788 @@ impl SillircApp {
     }
 
     fn ez_send(&self, message: SerializableMessage) {
+        self.ez_send_many(vec![message]);
+    }
+
+    /// Sends all of `messages` in order, for when one has to get there before the next.
+    fn ez_send_many(&self, messages: Vec<SerializableMessage>) {
+        let networker = self.networker.clone();
+        let notice = self.notice.clone();
+        self.runtime.spawn(async move {
+            let mut networker = networker.lock().await;
+            let Some(nw) = networker.as_mut() else {
+                return;
+            };
+            send_all(nw, messages, &notice).await;
+        });
+    }
+
+    /// Sends `message`, which shows up as pending for as long as it's in the outbox, and sticks
+    /// around as failed if it doesn't make it.
+    fn send_tracked(&self, message: SerializableMessage) {
         let networker = self.networker.clone();
-        let msg = message.clone();
+        let notice = self.notice.clone();
+        let failed = self.failed.clone();
         self.runtime.spawn(async move {
-            match networker.lock().await.as_mut() {
-                Some(nw) => nw,
-                None => return,
+            let delivery = {
+                let mut networker = networker.lock().await;
+                let Some(nw) = networker.as_mut() else {
+                    return;
+                };
+                nw.send(message.clone()).await
+            };
+            let delivery = match delivery {
+                Ok(delivery) => delivery,
+                Err(err) => {
+                    notice.lock().await.get_or_insert_with(|| err.to_string());
+                    return;
+                }
+            };
+
+            let client_id = delivery.get_client_id();
+            if let Err(err) = delivery.await {
+                failed
+                    .lock()
+                    .await
+                    .insert(client_id, (message, err.to_string()));
             }
-            .send(msg)
-            .await;
         });
-        drop(message);
+    }
+
+    /// Where the outbox lives between runs, so nothing typed while offline gets lost by closing
+    /// the app. It's only kept in memory if there's nowhere to put it.
+    fn open_outbox() -> Outbox {
+        eframe::storage_dir(APP_NAME)
+            .and_then(|dir| {
+                fs::create_dir_all(&dir).ok()?;
+                Outbox::open(dir.join("outbox.jsonl")).ok()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Extra certificates to trust for `wss://`, from the same `SILLIRC_CA` and `SILLIRC_PIN` as
+    /// `sillirc-mini`. If they can't be read, the notice says why and only the usual ones are used.
+    fn tls_options(&self) -> TlsOptions {
+        TlsOptions::from_env().unwrap_or_else(|err| {
+            self.notice
+                .blocking_lock()
+                .get_or_insert_with(|| format!("Couldn't read certificates: {err}"));
+            TlsOptions::default()
+        })
     }
 
     fn connect(&mut self) {
-        let networker = self.networker.clone();
-        if !self.is_connected {
+        if !self.connection_started {
             let messages = self.messages.clone();
-            let user = self.user.clone();
+            let typing = self.typing.clone();
+            let members = self.members.clone();
+            let incoming_error = self.incoming_error.clone();
+            let notice = self.notice.clone();
 
-            self.runtime.spawn(async move {
-                let mut nw = Networker::new("ws://sillirc.owomay.hackclub.app", move |message| {
+            let url = env::var("SILLIRC_SERVER").unwrap_or_else(|_| String::from(DEFAULT_SERVER));
+
+            let options = ConnectOptions::new()
+                .set_outbox(Self::open_outbox())
+                .set_tls(self.tls_options());
+            let nw = self
+                .runtime
+                .block_on(Networker::new(&url, options, move |message| {
                     let messages = messages.clone();
+                    let typing = typing.clone();
+                    let members = members.clone();
+                    let incoming_error = incoming_error.clone();
+                    let notice = notice.clone();
                     async move {
-                        messages.lock().await.push(message);
+                        let key = (message.get_room(), message.get_user().get_username());
+                        match message.get_message_type() {
+                            SerializableMessageType::Who
+                            | SerializableMessageType::Roster
+                            | SerializableMessageType::Online
+                            | SerializableMessageType::Offline => {
+                                update_members(&mut *members.lock().await, &message);
+                            }
+                            // The first error is usually the one that matters, anything after is
+                            // fallout from it. Ones about names go to the name prompt.
+                            SerializableMessageType::Error => {
+                                let slot =
+                                    if message.get_error().is_none_or(ErrorCode::is_about_nickname)
+                                    {
+                                        &incoming_error
+                                    } else {
+                                        &notice
+                                    };
+                                slot.lock()
+                                    .await
+                                    .get_or_insert_with(|| message.get_content());
+                            }
+                            SerializableMessageType::LoggedIn | SerializableMessageType::Ack => {}
+                            SerializableMessageType::Typing => {
+                                typing.lock().await.insert(key, Instant::now());
+                            }
+                            // Once someone actually says something they're done typing.
+                            SerializableMessageType::Text => {
+                                typing.lock().await.remove(&key);
+                                receive_message(&mut *messages.lock().await, message);
+                            }
+                            _ => receive_message(&mut *messages.lock().await, message),
+                        }
                     }
-                })
-                .await;
+                }));
+            // It's there before anything gets sent, so nothing gets dropped for want of one.
+            *self.networker.blocking_lock() = Some(nw);
 
-                if !user.is_unnamed() {
-                    nw.send(SerializableMessage::new(
-                        user,
+            if !self.user.is_unnamed() {
+                let joins = self.rooms.iter().map(|room| {
+                    SerializableMessage::new(
+                        self.user.clone(),
                         SerializableMessageType::Join,
                         String::new(),
-                    ))
-                    .await;
+                    )
+                    .set_room(room.clone())
+                });
+                let who = SerializableMessage::new(
+                    self.user.clone(),
+                    SerializableMessageType::Who,
+                    String::new(),
+                );
+                self.ez_send_many(joins.chain([who]).collect());
+            }
+
+            self.connection_started = true;
+        }
+    }
+
+    /// Shows what's going on with the connection, unless it's fine.
+    fn connection_status(&self, ui: &mut egui::Ui) {
+        let state = self
+            .networker
+            .blocking_lock()
+            .as_ref()
+            .map_or(ConnectionEvent::Connecting, Networker::get_connection_state);
+        if state.is_connected() {
+            return;
+        }
+
+        ui.colored_label(ui.visuals().warn_fg_color, state.to_string());
+        // Nothing else wakes us up when the connection changes.
+        ui.ctx().request_repaint_after(Duration::from_millis(250));
+    }
+
+    fn join_room(&mut self, room: String) {
+        if !self.rooms.contains(&room) {
+            self.ez_send(
+                SerializableMessage::new(
+                    self.user.clone(),
+                    SerializableMessageType::Join,
+                    String::new(),
+                )
+                .set_room(room.clone()),
+            );
+            self.rooms.push(room.clone());
+        }
+        self.current_room = room;
+    }
+
+    fn leave_room(&mut self, room: &str) {
+        self.ez_send(
+            SerializableMessage::new(
+                self.user.clone(),
+                SerializableMessageType::Leave,
+                String::new(),
+            )
+            .set_room(String::from(room)),
+        );
+        self.rooms.retain(|joined| joined != room);
+        if self.current_room == room {
+            self.current_room = self
+                .rooms
+                .first()
+                .cloned()
+                .unwrap_or_else(|| String::from(DEFAULT_ROOM));
+        }
+    }
+
+    fn rooms_panel(&mut self, ctx: &egui::Context) {
+        egui::SidePanel::left("rooms_panel").show(ctx, |ui| {
+            ui.heading("rooms");
+            for room in self.rooms.clone() {
+                if ui
+                    .selectable_label(room == self.current_room, format!("#{room}"))
+                    .clicked()
+                {
+                    self.current_room = room;
                 }
+            }
 
-                *networker.lock().await = Some(nw);
-            });
+            ui.separator();
+
+            let output = egui::TextEdit::singleline(&mut self.temp_room)
+                .hint_text("join a room")
+                .show(ui);
+            if output.response.lost_focus()
+                && ui.input(|i| i.key_pressed(egui::Key::Enter))
+                && !self.temp_room.is_empty()
+            {
+                let room = std::mem::take(&mut self.temp_room);
+                self.join_room(room.trim_start_matches('#').to_owned());
+            }
+
+            if self.rooms.len() > 1 && ui.button("leave room").clicked() {
+                let room = self.current_room.clone();
+                self.leave_room(&room);
+            }
+        });
+    }
+
+    fn members_panel(&self, ctx: &egui::Context) {
+        egui::SidePanel::right("members_panel").show(ctx, |ui| {
+            ui.heading("online");
+            let mut members = self.members.blocking_lock().clone();
+            members.sort_by_key(|member| member.get_username().to_lowercase());
+            for member in members {
+                let (r, g, b) = member.get_color();
+                ui.label(
+                    egui::RichText::new(member.get_username())
+                        .strong()
+                        .color(egui::Color32::from_rgb(r, g, b)),
+                );
+            }
+        });
+    }
+
+    /// The only things the server complains about are names that are taken or need logging in to,
+    /// so go back to whatever we were called before and ask again.
+    fn check_name_error(&mut self) {
+        let Some(error) = self.incoming_error.blocking_lock().take() else {
+            return;
+        };
+
+        // Without a previous name, it's the one we came back with from last time that's a problem,
+        // so start over as if we'd never picked one.
+        if let Some(previous) = self.previous_username.take() {
+            self.renaming = !previous.is_empty();
+            self.user = self.user.clone().set_username(previous);
+        } else {
+            self.temp_username = self.user.get_username();
+            self.user = self.user.clone().set_username(String::new());
+            self.renaming = false;
+        }
+        self.name_error = Some(error);
+    }
+
+    fn username_prompt(&mut self, ui: &mut egui::Ui) {
+        if self.renaming {
+            ui.label("what's your new name?");
+        } else {
+            ui.label("what should we call you? (can be changed in preferences)");
+        }
+        if let Some(error) = &self.name_error {
+            ui.colored_label(ui.visuals().error_fg_color, error);
+        }
+        let name = egui::TextEdit::singleline(&mut self.temp_username).show(ui);
+        let password = egui::TextEdit::singleline(&mut self.temp_password)
+            .password(true)
+            .hint_text("password, if the name is registered")
+            .show(ui);
+        let entered = (name.response.lost_focus() || password.response.lost_focus())
+            && ui.input(|i| i.key_pressed(egui::Key::Enter));
+        let register = ui.button("register this name").clicked();
+
+        if (entered || register) && !self.temp_username.is_empty() {
+            self.submit_username(register);
+        }
+    }
+
+    /// Logs in (or registers) if there's a password, then starts going by the new name.
+    fn submit_username(&mut self, register: bool) {
+        self.name_error = None;
+        self.previous_username = Some(self.user.get_username());
 
-            self.is_connected = true;
+        let password = std::mem::take(&mut self.temp_password);
+        let named = self.user.clone().set_username(self.temp_username.clone());
+        let mut messages = Vec::new();
+
+        if register {
+            messages.push(SerializableMessage::new(
+                named.clone(),
+                SerializableMessageType::Register,
+                password,
+            ));
+        } else if !password.is_empty() {
+            messages.push(SerializableMessage::new(
+                named.clone(),
+                SerializableMessageType::Login,
+                password,
+            ));
+        }
+
+        if self.renaming {
+            messages.push(
+                SerializableMessage::new(
+                    self.user.clone(),
+                    SerializableMessageType::Rename,
+                    self.temp_username.clone(),
+                )
+                .set_room(self.current_room.clone()),
+            );
+        } else {
+            for room in &self.rooms {
+                messages.push(
+                    SerializableMessage::new(
+                        named.clone(),
+                        SerializableMessageType::Join,
+                        String::new(),
+                    )
+                    .set_room(room.clone()),
+                );
+            }
+            messages.push(SerializableMessage::new(
+                named.clone(),
+                SerializableMessageType::Who,
+                String::new(),
+            ));
         }
+
+        self.user = named;
+        self.renaming = false;
+        self.ez_send_many(messages);
     }
 
-    fn render_message(message: &SerializableMessage, ui: &mut egui::Ui) {
+    /// The little quote above a reply, which jumps to the original when clicked.
+    fn render_reply_snippet(
+        reply_to: u64,
+        parent: Option<&SerializableMessage>,
+        ui: &mut egui::Ui,
+    ) -> Option<MessageAction> {
+        let snippet = match parent {
+            Some(parent) => {
+                let content = parent.get_content();
+                let mut snippet: String = content.chars().take(40).collect();
+                if snippet.len() < content.len() {
+                    snippet.push('…');
+                }
+                format!(
+                    "↳ replying to {}: {snippet}",
+                    parent.get_user().get_username()
+                )
+            }
+            None => format!("↳ replying to #{reply_to}"),
+        };
+
+        ui.add(
+            egui::Label::new(egui::RichText::new(snippet).small().weak())
+                .sense(egui::Sense::click()),
+        )
+        .on_hover_cursor(egui::CursorIcon::PointingHand)
+        .clicked()
+        .then_some(MessageAction::ShowOriginal(reply_to))
+    }
+
+    /// Whatever comes after the name, which depends on what kind of message it is.
+    fn render_body(message: &SerializableMessage, col: egui::Color32, ui: &mut egui::Ui) {
+        match message.get_message_type() {
+            SerializableMessageType::Join => {
+                ui.label("has joined the chat.");
+            }
+            SerializableMessageType::Leave => {
+                ui.label("has left the chat.");
+            }
+            // Renaming to the same name is how colors get changed.
+            SerializableMessageType::Rename
+                if message.get_content() == message.get_user().get_username() =>
+            {
+                ui.label("changed their color.");
+            }
+            SerializableMessageType::Rename => {
+                ui.label("changed their name to");
+                ui.label(
+                    egui::RichText::new(message.get_content().as_str())
+                        .strong()
+                        .color(col),
+                );
+            }
+            SerializableMessageType::Text => {
+                ui.label(message.get_content());
+                if message.is_edited() {
+                    ui.label(egui::RichText::new("(edited)").small().weak());
+                }
+            }
+            // These never make it into the message list. Some change what's already there, the rest
+            // are shown elsewhere.
+            SerializableMessageType::Edit
+            | SerializableMessageType::Delete
+            | SerializableMessageType::Reaction
+            | SerializableMessageType::Typing
+            | SerializableMessageType::Who
+            | SerializableMessageType::Roster
+            | SerializableMessageType::Online
+            | SerializableMessageType::Offline
+            | SerializableMessageType::Error
+            | SerializableMessageType::Register
+            | SerializableMessageType::Login
+            | SerializableMessageType::LoggedIn
+            | SerializableMessageType::Ack
+            | SerializableMessageType::Hello
+            | SerializableMessageType::Welcome => {}
+            SerializableMessageType::Direct => {
+                ui.label(
+                    egui::RichText::new(format!(
+                        "→ {}",
+                        message.get_recipient().unwrap_or_default()
+                    ))
+                    .italics()
+                    .color(col),
+                );
+                ui.label(egui::RichText::new(message.get_content()).italics());
+            }
+        }
+    }
+
+    fn render_message(
+        message: &SerializableMessage,
+        parent: Option<&SerializableMessage>,
+        is_own: bool,
+        scroll_here: bool,
+        ui: &mut egui::Ui,
+    ) -> Option<MessageAction> {
         ui.separator();
 
-        ui.horizontal(|ui| {
+        let mut action = message
+            .get_reply_to()
+            .and_then(|reply_to| Self::render_reply_snippet(reply_to, parent, ui));
+
+        let response = ui.horizontal(|ui| {
+            // Scrollback from before we showed up is drawn faded out.
+            if message.is_history() {
+                ui.multiply_opacity(0.6);
+            }
+
             let user = message.get_user();
-            let message_type = message.get_message_type();
             let (r, g, b) = user.get_color();
             let col = egui::Color32::from_rgb(r, g, b);
+            if let Some(time) = message.get_utc_time() {
+                let response = ui.label(egui::RichText::new(time).small().weak());
+                if let Some(id) = message.get_id() {
+                    response.on_hover_text(format!("#{id}"));
+                }
+            }
             ui.label(egui::RichText::new(user.get_username()).strong().color(col));
-            match message_type {
-                SerializableMessageType::Join => {
-                    ui.label("has joined the chat.");
+            Self::render_body(message, col, ui);
+        });
+
+        if scroll_here {
+            response.response.scroll_to_me(Some(egui::Align::Center));
+        }
+
+        let (SerializableMessageType::Text, Some(id)) =
+            (message.get_message_type(), message.get_id())
+        else {
+            return action;
+        };
+
+        let reactions = message.get_reactions();
+        if !reactions.is_empty() {
+            ui.horizontal(|ui| {
+                for (emoji, count) in reactions {
+                    if ui.small_button(format!("{emoji} {count}")).clicked() {
+                        action = Some(MessageAction::React(id, emoji));
+                    }
+                }
+            });
+        }
+
+        response
+            .response
+            .interact(egui::Sense::click())
+            .context_menu(|ui| {
+                ui.horizontal(|ui| {
+                    for emoji in QUICK_REACTIONS {
+                        if ui.button(emoji).clicked() {
+                            action = Some(MessageAction::React(id, String::from(emoji)));
+                        }
+                    }
+                });
+                if ui.button("reply").clicked() {
+                    action = Some(MessageAction::Reply(id));
                 }
-                SerializableMessageType::Leave => {
-                    ui.label("has left the chat.");
+                if is_own {
+                    if ui.button("edit").clicked() {
+                        action = Some(MessageAction::Edit(id, message.get_content()));
+                    }
+                    if ui.button("delete").clicked() {
+                        action = Some(MessageAction::Delete(id));
+                    }
+                }
+            });
+        action
+    }
+
+    fn message_list(&mut self, ui: &mut egui::Ui) {
+        let mut actions = Vec::new();
+
+        egui::ScrollArea::vertical()
+            .stick_to_bottom(true)
+            .max_height(ui.available_height() - 56.0)
+            .show(ui, |ui| {
+                let messages: Vec<_> = {
+                    let guard = self.messages.blocking_lock();
+                    guard.clone()
+                };
+                for message in messages.iter().filter(|message| {
+                    message.get_room() == self.current_room
+                        || matches!(
+                            message.get_message_type(),
+                            SerializableMessageType::Rename | SerializableMessageType::Direct
+                        )
+                }) {
+                    let author = message.get_user();
+                    let is_own = author.get_username() == self.user.get_username()
+                        && author.get_color() == self.user.get_color();
+                    let parent = message.get_reply_to().and_then(|reply_to| {
+                        messages
+                            .iter()
+                            .find(|parent| parent.get_id() == Some(reply_to))
+                    });
+                    let scroll_here =
+                        message.get_id().is_some() && message.get_id() == self.scroll_to;
+                    actions.extend(Self::render_message(
+                        message,
+                        parent,
+                        is_own,
+                        scroll_here,
+                        ui,
+                    ));
                 }
-                SerializableMessageType::Rename => {
-                    ui.label("changed their name to");
-                    ui.label(
-                        egui::RichText::new(message.get_content().as_str())
-                            .strong()
-                            .color(col),
+                self.outgoing_list(ui);
+            });
+
+        self.scroll_to = None;
+
+        for action in actions {
+            match action {
+                MessageAction::Edit(id, content) => {
+                    self.editing = Some(id);
+                    self.current_text = content;
+                }
+                MessageAction::Delete(id) => {
+                    self.ez_send(
+                        SerializableMessage::new(
+                            self.user.clone(),
+                            SerializableMessageType::Delete,
+                            String::new(),
+                        )
+                        .set_target(id)
+                        .set_room(self.current_room.clone()),
                     );
                 }
-                SerializableMessageType::Text => {
-                    ui.label(message.get_content());
+                MessageAction::Reply(id) => {
+                    self.replying_to = Some(id);
+                }
+                MessageAction::ShowOriginal(id) => {
+                    self.scroll_to = Some(id);
                 }
+                MessageAction::React(id, emoji) => {
+                    self.ez_send(
+                        SerializableMessage::new(
+                            self.user.clone(),
+                            SerializableMessageType::Reaction,
+                            emoji,
+                        )
+                        .set_target(id)
+                        .set_room(self.current_room.clone()),
+                    );
+                }
+            }
+        }
+    }
+
+    /// Our own messages that are still on their way, or didn't make it, in the current room.
+    fn outgoing_list(&self, ui: &mut egui::Ui) {
+        let (queued, connected) = match self.networker.blocking_lock().as_ref() {
+            Some(nw) => (nw.get_outbox(), nw.get_connection_state().is_connected()),
+            None => (Vec::new(), false),
+        };
+        let here = |message: &SerializableMessage| match message.get_message_type() {
+            SerializableMessageType::Text => message.get_room() == self.current_room,
+            SerializableMessageType::Direct => true,
+            _ => false,
+        };
+
+        for message in queued.iter().filter(|message| here(message)) {
+            self.outgoing_line(message, ui, |ui| {
+                let status = if connected { "sending..." } else { "queued" };
+                ui.label(egui::RichText::new(status).small().weak());
+            });
+        }
+
+        let mut failed = self.failed.blocking_lock();
+        let mut dismissed = Vec::new();
+        for (client_id, (message, error)) in failed.iter() {
+            if !here(message) {
+                continue;
             }
+            self.outgoing_line(message, ui, |ui| {
+                ui.colored_label(ui.visuals().error_fg_color, format!("didn't send: {error}"));
+                if ui.small_button("dismiss").clicked() {
+                    dismissed.push(*client_id);
+                }
+            });
+        }
+        for client_id in dismissed {
+            failed.remove(&client_id);
+        }
+    }
+
+    /// One of our own messages the server hasn't taken yet, greyed out, with whatever `status`
+    /// draws after it.
+    fn outgoing_line(
+        &self,
+        message: &SerializableMessage,
+        ui: &mut egui::Ui,
+        status: impl FnOnce(&mut egui::Ui),
+    ) {
+        ui.separator();
+        ui.horizontal(|ui| {
+            ui.multiply_opacity(0.5);
+            let (r, g, b) = self.user.get_color();
+            ui.label(
+                egui::RichText::new(self.user.get_username())
+                    .strong()
+                    .color(egui::Color32::from_rgb(r, g, b)),
+            );
+            ui.label(message.get_content());
+            status(ui);
         });
     }
+
+    fn input_bar(&mut self, ui: &mut egui::Ui) {
+        if let Some(notice) = self.notice.blocking_lock().as_ref() {
+            ui.colored_label(ui.visuals().error_fg_color, notice);
+        }
+
+        let response = ui.text_edit_singleline(&mut self.current_text);
+        if response.lost_focus()
+            && ui.input(|i| i.key_pressed(egui::Key::Enter))
+            && !self.current_text.is_empty()
+        {
+            self.send_current_text();
+        } else if response.changed() {
+            self.send_typing();
+        }
+
+        if response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+            response.request_focus();
+        }
+
+        if let Some(id) = self.editing {
+            ui.horizontal(|ui| {
+                if ui.small_button("cancel").clicked() {
+                    self.editing = None;
+                    self.current_text.clear();
+                }
+                ui.label(egui::RichText::new(format!("editing #{id}")).small().weak());
+            });
+        } else if let Some(id) = self.replying_to {
+            ui.horizontal(|ui| {
+                if ui.small_button("cancel").clicked() {
+                    self.replying_to = None;
+                }
+                ui.label(
+                    egui::RichText::new(format!("replying to #{id}"))
+                        .small()
+                        .weak(),
+                );
+            });
+        }
+
+        self.typing_label(ui);
+    }
+
+    /// Lets the room know we're typing, at most once every `TYPING_THROTTLE`.
+    fn send_typing(&mut self) {
+        // Commands and DMs aren't something the whole room needs to know about.
+        if self.current_text.is_empty() || self.current_text.starts_with('/') {
+            return;
+        }
+
+        if self
+            .last_typing_sent
+            .is_some_and(|sent| sent.elapsed() < TYPING_THROTTLE)
+        {
+            return;
+        }
+
+        self.last_typing_sent = Some(Instant::now());
+        self.ez_send(
+            SerializableMessage::new(
+                self.user.clone(),
+                SerializableMessageType::Typing,
+                String::new(),
+            )
+            .set_room(self.current_room.clone()),
+        );
+    }
+
+    fn typing_label(&self, ui: &mut egui::Ui) {
+        let mut typing = self.typing.blocking_lock();
+        typing.retain(|_, heard| heard.elapsed() < TYPING_TIMEOUT);
+
+        let mut names: Vec<&str> = typing
+            .keys()
+            .filter(|(room, _)| *room == self.current_room)
+            .map(|(_, username)| username.as_str())
+            .collect();
+        if typing.is_empty() {
+            return;
+        }
+        names.sort_unstable();
+
+        // Keep redrawing so the label goes away on its own once people stop.
+        ui.ctx().request_repaint_after(Duration::from_secs(1));
+
+        let text = match names.as_slice() {
+            [] => return,
+            [name] => format!("{name} is typing…"),
+            [rest @ .., last] => format!("{} and {last} are typing…", rest.join(", ")),
+        };
+        ui.label(egui::RichText::new(text).small().weak());
+    }
+
+    fn send_current_text(&mut self) {
+        let text = std::mem::take(&mut self.current_text);
+        *self.notice.blocking_lock() = None;
+
+        if let Some(id) = self.editing.take() {
+            self.ez_send(
+                SerializableMessage::new(self.user.clone(), SerializableMessageType::Edit, text)
+                    .set_target(id)
+                    .set_room(self.current_room.clone()),
+            );
+            return;
+        }
+
+        // IRC style `/msg <user> <text>` for direct messages, everything else goes to the room.
+        let message = if let Some((recipient, content)) = text
+            .strip_prefix("/msg ")
+            .and_then(|rest| rest.trim_start().split_once(' '))
+        {
+            SerializableMessage::new_direct(
+                self.user.clone(),
+                recipient.to_owned(),
+                content.to_owned(),
+            )
+        } else {
+            let message =
+                SerializableMessage::new(self.user.clone(), SerializableMessageType::Text, text)
+                    .set_room(self.current_room.clone());
+            match self.replying_to.take() {
+                Some(reply_to) => message.set_reply_to(reply_to),
+                None => message,
+            }
+        };
+
+        self.send_tracked(message);
+    }
 }
 
 impl Default for SillircApp {
@@ -127,14 +969,29 @@ impl Default for SillircApp {
                 .build()
                 .expect("Failed to create Tokio runtime"),
             networker: Arc::new(Mutex::new(None)),
-            is_connected: false,
+            connection_started: false,
             messages: Arc::new(Mutex::new(Vec::new())),
+            failed: Arc::new(Mutex::new(BTreeMap::new())),
             current_text: String::new(),
             temp_username: String::new(),
+            temp_password: String::new(),
             temp_color: [0, 0, 0],
             renaming: false,
             coloring: false,
+            temp_room: String::new(),
+            editing: None,
+            replying_to: None,
+            scroll_to: None,
+            typing: Arc::new(Mutex::new(HashMap::new())),
+            last_typing_sent: None,
+            members: Arc::new(Mutex::new(Vec::new())),
+            incoming_error: Arc::new(Mutex::new(None)),
+            notice: Arc::new(Mutex::new(None)),
+            name_error: None,
+            previous_username: None,
             user: User::new(String::new()),
+            rooms: vec![String::from(DEFAULT_ROOM)],
+            current_room: String::from(DEFAULT_ROOM),
         }
     }
 }
@@ -142,6 +999,7 @@ impl Default for SillircApp {
 impl App for SillircApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         self.connect();
+        self.check_name_error();
 
         egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
             egui::MenuBar::new().ui(ui, |ui| {
@@ -160,31 +1018,15 @@ impl App for SillircApp {
             });
         });
 
+        if !self.user.is_unnamed() {
+            self.rooms_panel(ctx);
+            self.members_panel(ctx);
+        }
+
         egui::CentralPanel::default().show(ctx, |ui| {
-            ui.heading("sillirc");
+            ui.heading(format!("sillirc #{}", self.current_room));
             if self.user.is_unnamed() || self.renaming {
-                if self.renaming {
-                    ui.label("what's your new name?");
-                } else {
-                    ui.label("what should we call you? (can be changed in preferences)");
-                }
-                let output = egui::TextEdit::singleline(&mut self.temp_username).show(ui);
-                if output.response.lost_focus()
-                    && ui.input(|i| i.key_pressed(egui::Key::Enter))
-                    && !self.temp_username.is_empty()
-                {
-                    self.ez_send(SerializableMessage::new(
-                        self.user.clone(),
-                        if self.renaming {
-                            SerializableMessageType::Rename
-                        } else {
-                            SerializableMessageType::Join
-                        },
-                        self.temp_username.clone(),
-                    ));
-                    self.user = self.user.clone().set_username(self.temp_username.clone());
-                    self.renaming = false;
-                }
+                self.username_prompt(ui);
             }
 
             if self.coloring {
@@ -195,25 +1037,24 @@ impl App for SillircApp {
                         .clone()
                         .set_color(<(u8, u8, u8)>::from(self.temp_color));
                     self.coloring = false;
+
+                    // The server only changes who we are when we rename, so rename to ourselves.
+                    if !self.user.is_unnamed() {
+                        self.ez_send(
+                            SerializableMessage::new(
+                                self.user.clone(),
+                                SerializableMessageType::Rename,
+                                self.user.get_username(),
+                            )
+                            .set_room(self.current_room.clone()),
+                        );
+                    }
                 }
             }
 
-            if self.networker.blocking_lock().is_none() {
-                ui.heading("YOU ARE NOT CONNECTED TO A SERVER!");
-            }
+            self.connection_status(ui);
 
-            egui::ScrollArea::vertical()
-                .stick_to_bottom(true)
-                .max_height(ui.available_height() - 56.0)
-                .show(ui, |ui| {
-                    let messages: Vec<_> = {
-                        let guard = self.messages.blocking_lock();
-                        guard.clone()
-                    };
-                    for message in messages {
-                        Self::render_message(&message, ui);
-                    }
-                });
+            self.message_list(ui);
 
             ui.with_layout(egui::Layout::bottom_up(egui::Align::RIGHT), |ui| {
                 egui::warn_if_debug_build(ui);
@@ -222,22 +1063,7 @@ impl App for SillircApp {
                 ui.separator();
 
                 if !self.user.is_unnamed() {
-                    let response = ui.text_edit_singleline(&mut self.current_text);
-                    if response.lost_focus()
-                        && ui.input(|i| i.key_pressed(egui::Key::Enter))
-                        && !self.current_text.is_empty()
-                    {
-                        self.ez_send(SerializableMessage::new(
-                            self.user.clone(),
-                            SerializableMessageType::Text,
-                            self.current_text.clone(),
-                        ));
-                        self.current_text = String::new();
-                    }
-
-                    if response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
-                        response.request_focus();
-                    }
+                    self.input_bar(ui);
                 }
             });
         });
@@ -248,10 +1074,15 @@ impl App for SillircApp {
     }
 
     fn on_exit(&mut self, _gl: Option<&Context>) {
-        self.ez_send(SerializableMessage::new(
-            self.user.clone(),
-            SerializableMessageType::Leave,
-            String::new(),
-        ));
+        for room in &self.rooms {
+            self.ez_send(
+                SerializableMessage::new(
+                    self.user.clone(),
+                    SerializableMessageType::Leave,
+                    String::new(),
+                )
+                .set_room(room.clone()),
+            );
+        }
     }
 }
@@ -1,4 +1,4 @@
 #![warn(clippy::all, rust_2018_idioms)]
 
 mod app;
-pub use app::SillircApp;
+pub use app::{APP_NAME, SillircApp};
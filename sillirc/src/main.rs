@@ -19,7 +19,7 @@ fn main() -> eframe::Result {
         ..Default::default()
     };
     eframe::run_native(
-        "sillirc",
+        sillirc::APP_NAME,
         native_options,
         Box::new(|cc| Ok(Box::new(sillirc::SillircApp::new(cc)))),
     )